@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::global_args;
+
+/// The contents of the config file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named set of encoder settings, e.g. `[profiles.anime-av1]`. Anything set on the command line
+/// overrides what's set here.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The FFmpeg encoder to use, e.g. 'libsvtav1' or 'libopus'.
+    pub encoder: Option<String>,
+    /// The target bitrate, e.g. '128k'. For video it's passed as '-b:v', which encoders in CRF
+    /// mode like SVT-AV1 treat as the highest bitrate.
+    pub bitrate: Option<String>,
+    /// Encoder options, passed to FFmpeg as '-<key> <value>'.
+    #[serde(default)]
+    pub params: BTreeMap<String, toml::Value>,
+    /// The pixel format of video streams, e.g. 'yuv420p10le'.
+    pub pixel_format: Option<String>,
+    /// Any other arguments to pass to FFmpeg, placed right before the output file.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    /// The container (file extension) of the output files.
+    pub container: Option<String>,
+}
+
+impl Profile {
+    /// Gets the encoder options as FFmpeg arguments, with `overrides` replacing or adding to the
+    /// ones from the profile.
+    pub fn params_args(&self, overrides: &[(&str, Option<String>)]) -> Vec<String> {
+        let mut params = self
+            .params
+            .iter()
+            .map(|(k, v)| (k.as_str(), value_to_string(v)))
+            .collect::<BTreeMap<_, _>>();
+        for (key, value) in overrides {
+            if let Some(value) = value {
+                params.insert(key, value.clone());
+            }
+        }
+        params
+            .into_iter()
+            .flat_map(|(k, v)| [format!("-{}", k), v])
+            .collect()
+    }
+}
+
+/// TOML strings would be written with quotes by `to_string`, which FFmpeg doesn't want.
+fn value_to_string(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Where the config file is read from if '--config' isn't given.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("mediatools").join("config.toml"))
+}
+
+/// Loads the config file. A missing config file is only an error if it was given explicitly.
+pub fn load() -> Result<Config> {
+    let path = match &global_args().config {
+        Some(path) => path.clone(),
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Config::default()),
+        },
+    };
+    let raw = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    toml::from_str(&raw).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+/// Loads the profile with the given name, or an empty one if no name was given.
+pub fn load_profile(name: Option<&str>) -> Result<Profile> {
+    let Some(name) = name else {
+        return Ok(Profile::default());
+    };
+    let mut config = load()?;
+    match config.profiles.remove(name) {
+        Some(profile) => Ok(profile),
+        None => bail!(
+            "There's no profile named '{}'! Run 'mediatools profiles list' to see all profiles.",
+            name
+        ),
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use simplelog::{error, info, warn};
+
+use crate::{
+    global_args,
+    plan::{self, Action},
+    probe,
+    progress::Progress,
+    utils::{self, FfmpegError},
+};
+
+/// Runs after a job's FFmpeg invocation succeeds. If it fails, the job fails.
+pub type AfterHook = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// Runs right before a job's FFmpeg invocation. If it fails, the job fails without running FFmpeg.
+type BeforeHook = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// Runs once a job is over, however it ended.
+type CleanupHook = Box<dyn Fn() + Send + Sync>;
+
+/// A single FFmpeg invocation that writes one output file.
+pub struct Job {
+    /// The name used to refer to the job in logs, usually the input file name.
+    name: String,
+    /// The file FFmpeg writes to.
+    output: PathBuf,
+    args: Vec<OsString>,
+    /// How much media the job will output. If not set, it's probed from the first input.
+    duration: Option<Duration>,
+    before: Vec<BeforeHook>,
+    after: Vec<AfterHook>,
+    /// What the `after` hooks would do, shown by a dry run.
+    planned: Vec<Action>,
+    cleanup: Vec<CleanupHook>,
+}
+
+impl Job {
+    pub fn new<I, S>(name: impl Into<String>, output: impl Into<PathBuf>, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        Self {
+            name: name.into(),
+            output: output.into(),
+            args: args.into_iter().map(|a| a.as_ref().to_owned()).collect(),
+            duration: None,
+            before: Vec::new(),
+            after: Vec::new(),
+            planned: Vec::new(),
+            cleanup: Vec::new(),
+        }
+    }
+
+    /// Adds something to do right before FFmpeg runs, e.g. writing a file it reads, so nothing is
+    /// made for jobs that never start. Hooks run in the order they're added, and aren't run in a
+    /// dry run.
+    pub fn before(mut self, hook: impl Fn() -> Result<()> + Send + Sync + 'static) -> Self {
+        self.before.push(Box::new(hook));
+        self
+    }
+
+    /// Adds something to do once FFmpeg has successfully written the output, e.g. recording that
+    /// it's done. Hooks run in the order they're added, and aren't run in a dry run.
+    pub fn then(mut self, hook: impl Fn() -> Result<()> + Send + Sync + 'static) -> Self {
+        self.after.push(Box::new(hook));
+        self
+    }
+
+    /// Records something a `then` hook does, e.g. replacing a file, so a dry run can show it after
+    /// the FFmpeg command.
+    pub fn plans(mut self, action: Action) -> Self {
+        self.planned.push(action);
+        self
+    }
+
+    /// Adds something to do once the job is over, whether it succeeded, failed, was skipped or
+    /// never started, e.g. removing temporary files. Hooks aren't run in a dry run.
+    pub fn finally(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.cleanup.push(Box::new(hook));
+        self
+    }
+
+    /// Sets how much media the job will output, for when it isn't the whole input, e.g. when
+    /// cutting a file.
+    pub fn with_duration(mut self, duration: Option<Duration>) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Whether FFmpeg is told to overwrite the output if it already exists.
+    fn overwrites(&self) -> bool {
+        self.args.iter().any(|a| a == "-y")
+    }
+
+    /// The first file passed to FFmpeg with '-i'.
+    fn input(&self) -> Option<&Path> {
+        self.args
+            .iter()
+            .skip_while(|a| *a != "-i")
+            .nth(1)
+            .map(Path::new)
+    }
+
+    /// Runs the job, then cleans up after it whether it succeeded or not.
+    fn run(&self, qffmpeg: bool, progress: &Progress) -> Result<()> {
+        let result = self.run_ffmpeg(qffmpeg, progress);
+        self.clean_up();
+        result
+    }
+
+    fn run_ffmpeg(&self, qffmpeg: bool, progress: &Progress) -> Result<()> {
+        // create_dir_all doesn't fail if another job creates the directory first.
+        if let Some(parent) = self.output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        for hook in &self.before {
+            hook()?;
+        }
+        let job_progress = progress.start_job(&self.name, self.duration);
+        let result = utils::run_ffmpeg(qffmpeg, &self.args, |p| job_progress.update(p));
+        job_progress.finish();
+        result?;
+        for hook in &self.after {
+            hook()?;
+        }
+        Ok(())
+    }
+
+    fn clean_up(&self) {
+        for hook in &self.cleanup {
+            hook();
+        }
+    }
+}
+
+/// A job that failed, as written to the failure report.
+#[derive(Debug, Serialize)]
+struct Failure {
+    name: String,
+    input: Option<PathBuf>,
+    output: PathBuf,
+    args: Vec<String>,
+    error: String,
+    stderr_tail: Option<String>,
+}
+
+impl Failure {
+    fn new(job: &Job, err: &anyhow::Error) -> Self {
+        Self {
+            name: job.name.clone(),
+            input: job.input().map(Path::to_path_buf),
+            output: job.output.clone(),
+            args: job
+                .args
+                .iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect(),
+            error: format!("{:#}", err),
+            stderr_tail: err
+                .downcast_ref::<FfmpegError>()
+                .and_then(FfmpegError::stderr_tail)
+                .map(str::to_string),
+        }
+    }
+}
+
+/// A file that was left out, and why.
+struct Skipped {
+    name: String,
+    reason: String,
+}
+
+/// Collects FFmpeg jobs and runs them on up to `--jobs` threads at once.
+pub struct JobQueue {
+    jobs: Vec<Job>,
+    /// Files that were decided against before any job was run.
+    skipped: Vec<Skipped>,
+    qffmpeg: bool,
+}
+
+impl JobQueue {
+    pub fn new(qffmpeg: bool) -> Self {
+        Self {
+            jobs: Vec::new(),
+            skipped: Vec::new(),
+            qffmpeg,
+        }
+    }
+
+    pub fn push(&mut self, job: Job) {
+        self.jobs.push(job);
+    }
+
+    /// The files the queued jobs write to.
+    pub fn outputs(&self) -> impl Iterator<Item = &Path> {
+        self.jobs.iter().map(|j| j.output.as_path())
+    }
+
+    /// Records that a file won't be processed, so it shows up in the summary.
+    pub fn skip(&mut self, path: &Path, reason: impl Into<String>) {
+        let reason = reason.into();
+        // A dry run lists skipped files along with everything else it would do.
+        if !plan::is_dry_run() {
+            warn!("Skipping {}: {}", path.display(), reason);
+        }
+        self.skipped.push(Skipped {
+            name: path.display().to_string(),
+            reason,
+        });
+    }
+
+    /// Runs all queued jobs. If a job fails, no new jobs are started unless `--keep-going` is set,
+    /// but the ones already running are allowed to finish.
+    pub fn run(mut self) -> Result<()> {
+        if self.jobs.is_empty() && (self.skipped.is_empty() || !plan::is_dry_run()) {
+            info!("Nothing to do.");
+            report_skipped(&self.skipped);
+            return Ok(());
+        }
+
+        // Two jobs writing the same file at once would corrupt it, and '-n' can't catch it since
+        // neither of them exists yet when FFmpeg checks.
+        let mut outputs = HashSet::new();
+        for job in &self.jobs {
+            if !outputs.insert(&job.output) {
+                bail!(
+                    "Multiple jobs would write to {}! Aborting before anything is run.",
+                    job.output.display()
+                );
+            }
+        }
+
+        if plan::is_dry_run() {
+            return self.plan();
+        }
+
+        let workers = global_args().jobs.get().min(self.jobs.len());
+        self.probe_durations(workers);
+        let progress = Progress::new(
+            self.jobs.iter().filter_map(|j| j.duration).sum(),
+            self.jobs.len(),
+        );
+
+        let next = AtomicUsize::new(0);
+        let succeeded = AtomicUsize::new(0);
+        let skipped = Mutex::new(Vec::new());
+        let failures = Mutex::new(Vec::new());
+        let abort = AtomicBool::new(false);
+        let start = Instant::now();
+
+        thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| {
+                    while !abort.load(Ordering::Relaxed) {
+                        let Some(job) = self.jobs.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                            break;
+                        };
+                        match job.run(self.qffmpeg, &progress) {
+                            Ok(()) => {
+                                succeeded.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e)
+                                if matches!(
+                                    e.downcast_ref(),
+                                    Some(FfmpegError::OutputExists { .. })
+                                ) =>
+                            {
+                                warn!("[{}] Skipping: {:#}", job.name, e);
+                                skipped
+                                    .lock()
+                                    .unwrap_or_else(|e| e.into_inner())
+                                    .push(Skipped {
+                                        name: job.name.clone(),
+                                        reason: format!("{:#}", e),
+                                    });
+                            }
+                            Err(e) => {
+                                error!("[{}] {:#}", job.name, e);
+                                failures
+                                    .lock()
+                                    .unwrap_or_else(|e| e.into_inner())
+                                    .push(Failure::new(job, &e));
+                                if !global_args().keep_going {
+                                    abort.store(true, Ordering::Relaxed);
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        progress.finish();
+        // Jobs are started in order, so the ones that weren't are at the end.
+        let started = next.into_inner().min(self.jobs.len());
+        for job in &self.jobs[started..] {
+            job.clean_up();
+        }
+
+        let succeeded = succeeded.into_inner();
+        let mut skipped = skipped.into_inner().unwrap_or_else(|e| e.into_inner());
+        let mut failures = failures.into_inner().unwrap_or_else(|e| e.into_inner());
+        let failed = failures.len();
+        let not_started = self.jobs.len() - succeeded - skipped.len() - failed;
+        info!(
+            "Finished {} job(s) in {:.1?} using {} worker(s): {} succeeded, {} skipped, {} failed, {} not started.",
+            self.jobs.len(),
+            start.elapsed(),
+            workers,
+            succeeded,
+            self.skipped.len() + skipped.len(),
+            failed,
+            not_started
+        );
+        skipped.sort_by_key(|s| self.jobs.iter().position(|j| j.name == s.name));
+        self.skipped.append(&mut skipped);
+        report_skipped(&self.skipped);
+
+        if failed > 0 {
+            // Jobs finish in whatever order, but the report should match the order they were
+            // queued in.
+            failures.sort_by_key(|f| self.jobs.iter().position(|j| j.output == f.output));
+            report_failures(&failures)?;
+            bail!(
+                "{} job(s) failed!{}",
+                failed,
+                if self.qffmpeg {
+                    " Run without '-q' or '--qffmpeg' to see the full ffmpeg output for why it failed."
+                } else {
+                    ""
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Records what each job would do instead of running it.
+    fn plan(&self) -> Result<()> {
+        for skipped in &self.skipped {
+            plan::record(Action::Skip {
+                path: PathBuf::from(&skipped.name),
+                reason: skipped.reason.clone(),
+            });
+        }
+        let mut skipped = self.skipped.len();
+        for job in &self.jobs {
+            if !job.overwrites() && job.output.exists() {
+                plan::record(Action::Skip {
+                    path: job.output.clone(),
+                    reason: "it already exists".to_string(),
+                });
+                skipped += 1;
+                continue;
+            }
+            if let Some(parent) = job.output.parent() {
+                plan::create_dir_all(parent)?;
+            }
+            plan::record(Action::Ffmpeg {
+                command: plan::ffmpeg_command(job.args.iter().map(|a| a.to_string_lossy())),
+                output: job.output.clone(),
+            });
+            for action in &job.planned {
+                plan::record(action.clone());
+            }
+        }
+        info!(
+            "Dry run: {} job(s) would run, {} would be skipped.",
+            self.jobs.len() + self.skipped.len() - skipped,
+            skipped
+        );
+        Ok(())
+    }
+
+    /// Probes the duration of every job that doesn't have one yet, using `workers` threads.
+    fn probe_durations(&mut self, workers: usize) {
+        let jobs = Mutex::new(self.jobs.iter_mut().filter(|j| j.duration.is_none()));
+        thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| loop {
+                    let Some(job) = jobs.lock().unwrap_or_else(|e| e.into_inner()).next() else {
+                        break;
+                    };
+                    job.duration = job.input().and_then(|i| probe::duration(i).ok());
+                });
+            }
+        });
+    }
+}
+
+/// Calls `f` on every item on up to `--jobs` threads at once, keeping the results in order. For
+/// work that doesn't fit a [`Job`], like analysing files before their jobs can be made.
+pub fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = Mutex::new(items.iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    thread::scope(|s| {
+        for _ in 0..global_args().jobs.get().min(items.len()) {
+            s.spawn(|| loop {
+                let Some((i, item)) = next.lock().unwrap_or_else(|e| e.into_inner()).next() else {
+                    break;
+                };
+                let result = f(item);
+                results
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    results.sort_unstable_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Prints a table of all skipped files and why they were skipped.
+fn report_skipped(skipped: &[Skipped]) {
+    if skipped.is_empty() {
+        return;
+    }
+    let rows = skipped
+        .iter()
+        .map(|s| vec![s.name.clone(), s.reason.clone()])
+        .collect::<Vec<_>>();
+    warn!("Skipped files:");
+    for line in utils::format_table(&["File", "Reason"], &rows) {
+        warn!("  {}", line);
+    }
+}
+
+/// Prints a table of all failed jobs, and writes them to the failure report if requested.
+fn report_failures(failures: &[Failure]) -> Result<()> {
+    let rows = failures
+        .iter()
+        .map(|f| vec![f.name.clone(), f.error.clone()])
+        .collect::<Vec<_>>();
+    error!("Failed jobs:");
+    for line in utils::format_table(&["File", "Error"], &rows) {
+        error!("  {}", line);
+    }
+
+    if let Some(path) = &global_args().failure_report {
+        fs::write(path, serde_json::to_string_pretty(failures)?)
+            .with_context(|| format!("Failed to write failure report to {}", path.display()))?;
+        info!("Wrote failure report to {}", path.display());
+    }
+    Ok(())
+}
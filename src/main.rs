@@ -1,24 +1,100 @@
-use std::path::PathBuf;
+use std::{num::NonZeroUsize, path::PathBuf, sync::OnceLock};
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args, Parser, Subcommand,
+};
+use glob::Pattern;
 use log::LevelFilter;
+use plan::PlanFormat;
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 use tools::{
-    cleanup_file_names, merge_videos, set_default_tracks, split_audio, transcode_audio,
+    cleanup_file_names, loudness, merge_videos, probe_media, profiles, set_default_tracks,
+    split_audio,
+    split_audio::{silence::Detection, SplitOptions},
+    transcode_audio,
+    transcode_audio::{
+        CompanionPolicy, Companions, Conversion, CoverArt, Dither, Downmix, DownmixMatrix,
+        LossyPolicy, Normalize, TranscodeOptions,
+    },
     transcode_video,
 };
 
+mod config;
+mod jobs;
+mod plan;
+mod probe;
+mod progress;
 mod tools;
 mod utils;
 
+static GLOBAL_ARGS: OnceLock<GlobalArgs> = OnceLock::new();
+
+/// Batch tools for transcoding, splitting and tagging audio and video with FFmpeg.
 #[derive(Debug, Parser)]
 #[command(name = "mediatools")]
 pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+// Options shared by all commands. Not a doc comment, since clap would use it as the app's
+// description.
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// The maximum number of FFmpeg processes to run at once.
+    #[clap(long, short, global = true, default_value = "1")]
+    pub jobs: NonZeroUsize,
+    /// Keep running the remaining jobs when one fails instead of stopping. Still exits with an
+    /// error at the end if anything failed.
+    #[clap(long, short, global = true)]
+    pub keep_going: bool,
+    /// Write a JSON report of all failed jobs to this file.
+    #[clap(long, global = true)]
+    pub failure_report: Option<PathBuf>,
+    /// Show what would be done without running FFmpeg, renaming files or creating directories.
+    #[clap(long, global = true)]
+    pub dry_run: bool,
+    /// How to show the plan of a dry run. A JSON plan is the only thing written to stdout, so it
+    /// can be redirected to a file.
+    #[clap(long, global = true, value_enum, default_value_t = PlanFormat::Text)]
+    pub plan_format: PlanFormat,
+    /// The config file to read profiles from. Defaults to ~/.config/mediatools/config.toml.
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub walk: WalkArgs,
+}
+
+/// Options for how commands search directories for files.
+#[derive(Debug, Clone, Args)]
+pub struct WalkArgs {
+    /// Search subdirectories too, mirroring the directory structure into the destination.
+    /// transcode-audio always does this.
+    #[clap(long, short, global = true)]
+    pub recursive: bool,
+    /// How many levels of directories to search when recursing. 1 only searches the given
+    /// directory itself.
+    #[clap(long, global = true)]
+    pub max_depth: Option<NonZeroUsize>,
+    /// Only process files whose path (relative to the searched directory) matches this glob.
+    /// Can be given multiple times.
+    #[clap(long, global = true)]
+    pub include: Vec<Pattern>,
+    /// Skip files and directories whose path (relative to the searched directory) matches this
+    /// glob. Can be given multiple times.
+    #[clap(long, global = true)]
+    pub exclude: Vec<Pattern>,
+}
+
+/// Gets the options shared by all commands.
+pub fn global_args() -> &'static GlobalArgs {
+    GLOBAL_ARGS.get().expect("global args not initialized")
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Removes all IDs in square brackets from directory/file names recursively.
@@ -31,18 +107,138 @@ pub enum Commands {
     TranscodeAudio {
         /// The source directory to transcode from.
         src_path: PathBuf,
-        /// The container (file extension) to search for. Only files with this file extension will
-        /// be transcoded.
+        /// The containers (file extensions) to search for, separated by commas. Only files with
+        /// one of these extensions will be transcoded. Case doesn't matter and globs are
+        /// supported, e.g. 'flac,wav' or 'm4?'. Use 'auto' to transcode every audio file, which
+        /// requires probing each file. Files with video other than cover art, like music videos,
+        /// aren't audio files and are left out.
         src_container: String,
         /// The destination path. If it doesn't exist, it will be created. The first layer of files
         /// in the source directory will be placed directly in this folder.
-        dest_path: PathBuf,
-        /// The bitrate of the transcoded files.
-        bitrate: String,
-        /// The codec to be used for encoding the file.
-        codec: String,
-        /// The container to place the files in.
-        container: String,
+        #[clap(required_unless_present = "recompress")]
+        dest_path: Option<PathBuf>,
+        /// The bitrate of the transcoded files. Overrides the profile's bitrate. Not needed for
+        /// lossless codecs or with '--vbr'.
+        bitrate: Option<String>,
+        /// The codec to be used for encoding the file. Overrides the profile's encoder.
+        codec: Option<String>,
+        /// The container to place the files in. Overrides the profile's container.
+        container: Option<String>,
+        /// Recompress lossless files in place instead of transcoding them elsewhere: WAV and AIFF
+        /// become FLAC, and FLAC is encoded again at '--compression' (8 by default). Originals are
+        /// only replaced once the decoded audio has been verified to be identical.
+        #[clap(long, conflicts_with_all = ["dest_path", "profile", "vbr", "sync"])]
+        recompress: bool,
+        /// The profile from the config file to take the encoder settings from.
+        #[clap(long)]
+        profile: Option<String>,
+        /// Encode with a VBR quality level instead of a bitrate. The scale depends on the codec:
+        /// 0-9 for MP3 (like LAME's -V, 0 is best), -1-10 for Vorbis, 1-5 for libfdk_aac and
+        /// 0.1-2 for FFmpeg's AAC (higher is better). Overrides the bitrate.
+        #[clap(
+            long,
+            visible_alias = "quality",
+            value_name = "LEVEL",
+            allow_negative_numbers = true
+        )]
+        vbr: Option<f32>,
+        /// The compression level of lossless codecs: 0-12 for FLAC, 0-8 for WavPack and 0-2 for
+        /// ALAC. Higher levels are smaller but slower to encode. Overrides the bitrate.
+        #[clap(long, value_name = "LEVEL", conflicts_with = "vbr")]
+        compression: Option<u8>,
+        /// Only transcode files that are new or have changed since the last sync. Outputs that
+        /// weren't made by a sync are kept if they're newer than their source.
+        #[clap(long, conflicts_with = "overwrite")]
+        sync: bool,
+        /// When syncing, detect changed files by their contents instead of their modification
+        /// time. Slower, but touching a file won't make it be transcoded again.
+        #[clap(long, requires = "sync")]
+        sync_hash: bool,
+        /// When syncing, delete outputs whose source file has been removed, or that were replaced
+        /// by an output at a different path (like after changing the codec or container).
+        #[clap(long, requires = "sync")]
+        delete_orphans: bool,
+        /// What to do with companion files (cover art, cue sheets, logs, lyrics) next to the
+        /// source files.
+        #[clap(long, value_enum, default_value = "skip")]
+        companions: CompanionPolicy,
+        /// The extensions of companion files, separated by commas.
+        #[clap(
+            long,
+            value_delimiter = ',',
+            default_value = "jpg,jpeg,png,webp,cue,log,lrc,txt,pdf"
+        )]
+        companion_ext: Vec<String>,
+        /// Downscale cover images so neither side is larger than this many pixels.
+        #[clap(long, value_name = "PX")]
+        cover_max_size: Option<u32>,
+        /// Recompress JPEG cover images with this quality (2-31, lower is better).
+        #[clap(long, value_parser = clap::value_parser!(u8).range(2..=31))]
+        cover_quality: Option<u8>,
+        /// Embed cover art into the transcoded files. Art already embedded in the source is
+        /// preferred, otherwise an image next to it is used (see '--cover-names').
+        #[clap(long)]
+        embed_cover: bool,
+        /// The names of the images to embed if a source file has no cover of its own, separated by
+        /// commas and in order of preference. Case doesn't matter.
+        #[clap(
+            long,
+            requires = "embed_cover",
+            value_delimiter = ',',
+            default_value = "cover.jpg,cover.png,folder.jpg,folder.png,front.jpg,front.png"
+        )]
+        cover_names: Vec<String>,
+        /// Downscale embedded covers so neither side is larger than this many pixels.
+        #[clap(long, requires = "embed_cover", value_name = "PX")]
+        embed_cover_max_size: Option<u32>,
+        /// What to do with sources that are already lossy, since transcoding them again loses
+        /// quality. Only applies when transcoding to a lossy codec.
+        #[clap(long, value_enum, default_value = "warn")]
+        lossy_source: LossyPolicy,
+        /// Transcode lossy sources even if the target bitrate is higher than theirs, which only
+        /// makes the files bigger.
+        #[clap(long)]
+        allow_upscale: bool,
+        /// Tag the transcoded files with their ReplayGain track and album gain (or R128 gain for
+        /// Opus) once they're done, like the 'loudness' command.
+        #[clap(long)]
+        replaygain: bool,
+        /// Normalize the loudness of each file to this many LUFS, e.g. -16 for podcasts or -23 for
+        /// broadcast. Each file is measured first, and the measurements are cached.
+        #[clap(long, value_name = "LUFS", allow_negative_numbers = true)]
+        normalize: Option<f64>,
+        /// The highest true peak allowed when normalizing, in dBTP.
+        #[clap(
+            long,
+            value_name = "DBTP",
+            requires = "normalize",
+            allow_negative_numbers = true,
+            default_value = "-1.5"
+        )]
+        true_peak: f64,
+        /// Resample sources with a higher sample rate than this, in Hz. Uses the SoX resampler if
+        /// FFmpeg has it.
+        #[clap(long, value_name = "HZ")]
+        sample_rate: Option<u32>,
+        /// Reduce sources with a higher bit depth than this: 16, 24 or 32. Only applies to lossless
+        /// codecs.
+        #[clap(
+            long,
+            value_name = "BITS",
+            value_parser = PossibleValuesParser::new(["16", "24", "32"])
+                .map(|s| s.parse::<u8>().expect("only numbers are possible"))
+        )]
+        bit_depth: Option<u8>,
+        /// How to dither when reducing to 16 bits. Defaults to triangular-hp. Higher bit depths are
+        /// truncated without dithering.
+        #[clap(long, value_enum)]
+        dither: Option<Dither>,
+        /// Downmix sources with more channels than this.
+        #[clap(long, value_enum)]
+        downmix: Option<Downmix>,
+        /// The matrix used when downmixing to stereo.
+        #[clap(long, value_enum, requires = "downmix", default_value = "default")]
+        downmix_matrix: DownmixMatrix,
         /// Force overwrite any existing files.
         #[clap(long, short)]
         overwrite: bool,
@@ -58,16 +254,20 @@ pub enum Commands {
         src_path: PathBuf,
         /// The destination path. If it doesn't exist, it will be created.
         dest_path: PathBuf,
-        /// SVT-AV1 preset.
-        preset: u8,
-        /// SVT-AV1 CRF.
-        crf: u8,
-        /// SVT-AV1 Keyframe internal. (120 = 5sec at 24fps).
-        keyframe_interval: u16,
+        /// SVT-AV1 preset. Required unless a profile is used.
+        preset: Option<u8>,
+        /// SVT-AV1 CRF. Required unless a profile is used.
+        crf: Option<u8>,
+        /// SVT-AV1 Keyframe internal. (120 = 5sec at 24fps). Required unless a profile is used.
+        keyframe_interval: Option<u16>,
         /// Forces SVT-AV1 to encode with 10-bit color. This can slightly improve quality even on
         /// an 8-bit source.
         #[clap(long, short)]
         force_10bit: bool,
+        /// The profile from the config file to take the encoder settings from. The preset, CRF and
+        /// keyframe interval override the profile's params.
+        #[clap(long)]
+        profile: Option<String>,
         /// Force overwrite any existing files.
         #[clap(long, short)]
         overwrite: bool,
@@ -125,15 +325,62 @@ pub enum Commands {
         /// The path to the file to split.
         src_file: PathBuf,
         /// The directory to put the split up files.
-        dest_path: PathBuf,
-        /// The file containing the timestampes used to split and label each file.
-        timestamps_file: PathBuf,
-        /// The artist of the audio.
+        #[clap(required_unless_present = "detect_silence")]
+        dest_path: Option<PathBuf>,
+        /// The file containing the timestampes used to split and label each file. Each line is
+        /// 'DISC TRACK TITLE START', or with '# mediatools timestamps v2' as the first line,
+        /// 'START[-END] | [DISC] | [TRACK] | TITLE [| artist=..] [| composer=..] [| genre=..]'
+        /// where times can have milliseconds and empty numbers count on from the previous track.
+        /// Lines starting with '#' are comments. A cue sheet ('.cue') can be used instead. If it
+        /// names more than one file, those files are split and the source file is ignored. With
+        /// '--from-chapters', it only overrides the titles of the tracks it lists.
+        #[clap(required_unless_present_any = ["from_chapters", "detect_silence"])]
+        timestamps_file: Option<PathBuf>,
+        /// The artist of the audio. Overrides any from a cue sheet or the source's tags.
         artist: Option<String>,
-        /// The album of the audio.
+        /// The album of the audio. Overrides any from a cue sheet or the source's tags.
         album: Option<String>,
-        /// The date of the audio (year-only).
+        /// The date of the audio (year-only). Overrides any from a cue sheet or the source's tags.
         date: Option<String>,
+        /// Split at the chapters of the source file, e.g. an audiobook or a concert video, instead
+        /// of at timestamps. Each chapter becomes a track named after it.
+        #[clap(long)]
+        from_chapters: bool,
+        /// Instead of splitting, find the silences between tracks and write a timestamps file
+        /// with a track starting in each, for you to check and name before splitting with it.
+        #[clap(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["dest_path", "from_chapters"]
+        )]
+        detect_silence: Option<PathBuf>,
+        /// Cut each track on the exact sample, so consecutive tracks play back without gaps or
+        /// clicks. The audio is encoded again: losslessly for lossless sources, and with the same
+        /// codec and bitrate for lossy ones. Without this, tracks are copied and can only be cut
+        /// between packets.
+        #[clap(long, conflicts_with = "detect_silence")]
+        accurate: bool,
+        /// The level below which audio counts as silence, in dB (e.g. '-50dB') or as an amplitude
+        /// ratio (e.g. '0.003').
+        #[clap(
+            long,
+            requires = "detect_silence",
+            allow_hyphen_values = true,
+            default_value = "-50dB"
+        )]
+        noise: String,
+        /// How long a silence must be to split at, in seconds.
+        #[clap(
+            long,
+            requires = "detect_silence",
+            value_name = "SECS",
+            default_value = "2"
+        )]
+        min_silence: f64,
+        /// The number of tracks expected. Only the longest silences are split at, so there's one
+        /// fewer of them.
+        #[clap(long, requires = "detect_silence")]
+        tracks: Option<usize>,
         /// Force overwrite any existing files.
         #[clap(long, short)]
         overwrite: bool,
@@ -142,20 +389,80 @@ pub enum Commands {
         #[clap(long, short)]
         qffmpeg: bool,
     },
+    /// Lists or shows the encoder profiles defined in the config file.
+    #[command(arg_required_else_help = true)]
+    Profiles {
+        #[command(subcommand)]
+        command: ProfilesCommand,
+    },
+    /// Measures the loudness of audio files and tags them with their ReplayGain track and album
+    /// gain (or R128 gain for Opus) without re-encoding them. Each directory is an album.
+    #[command(arg_required_else_help = true)]
+    Loudness {
+        /// The file or directory to tag.
+        path: PathBuf,
+        /// Hides FFmpeg's output. If commands aren't working as expected, omit this flag to see
+        /// what's going on.
+        #[clap(long, short)]
+        qffmpeg: bool,
+    },
+    /// Prints the format, streams and chapters of a media file.
+    #[command(arg_required_else_help = true)]
+    Probe {
+        /// The file to inspect.
+        path: PathBuf,
+        /// Print the information as JSON instead of a table.
+        #[clap(long)]
+        json: bool,
+    },
 }
+
+#[derive(Debug, Subcommand)]
+pub enum ProfilesCommand {
+    /// Lists the names of all profiles.
+    List,
+    /// Shows all settings of a profile.
+    #[command(arg_required_else_help = true)]
+    Show { name: String },
+}
+
 fn main() -> Result<()> {
-    TermLogger::init(
+    let args = Cli::parse();
+    // A JSON plan is printed to stdout, so everything else goes to stderr to keep it valid JSON.
+    let terminal_mode = if args.global.dry_run && args.global.plan_format == PlanFormat::Json {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+    progress::init_logger(
+        TermLogger::new(
+            LevelFilter::Info,
+            simplelog::Config::default(),
+            terminal_mode,
+            ColorChoice::Auto,
+        ),
         LevelFilter::Info,
-        simplelog::Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )
-    .with_context(|| "Failed to initialize logger!")?;
+    )?;
 
-    let args = Cli::parse();
+    GLOBAL_ARGS
+        .set(args.global)
+        .expect("global args already initialized");
 
     match args.command {
         Commands::CleanupFileNames { path } => cleanup_file_names::run(path)?,
+        Commands::TranscodeAudio {
+            src_path,
+            src_container,
+            recompress: true,
+            compression,
+            qffmpeg,
+            ..
+        } => transcode_audio::recompress::run(
+            &src_path,
+            &src_container,
+            compression.unwrap_or(transcode_audio::recompress::DEFAULT_LEVEL),
+            qffmpeg,
+        )?,
         Commands::TranscodeAudio {
             src_path,
             src_container,
@@ -163,16 +470,72 @@ fn main() -> Result<()> {
             bitrate,
             codec,
             container,
+            profile,
+            vbr,
+            compression,
+            sync,
+            sync_hash,
+            delete_orphans,
+            companions,
+            companion_ext,
+            cover_max_size,
+            cover_quality,
+            embed_cover,
+            cover_names,
+            embed_cover_max_size,
+            lossy_source,
+            allow_upscale,
+            replaygain,
+            normalize,
+            true_peak,
+            sample_rate,
+            bit_depth,
+            dither,
+            downmix,
+            downmix_matrix,
             overwrite,
             qffmpeg,
+            ..
         } => transcode_audio::run(
             src_path.as_path(),
             &src_container,
-            dest_path.as_path(),
-            &bitrate,
-            &codec,
-            &container,
-            overwrite,
+            dest_path.as_deref().context("No destination path given!")?,
+            TranscodeOptions {
+                bitrate: bitrate.as_deref(),
+                vbr,
+                compression,
+                codec: codec.as_deref(),
+                container: container.as_deref(),
+                profile: profile.as_deref(),
+                sync,
+                sync_hash,
+                delete_orphans,
+                companions: Companions {
+                    policy: companions,
+                    extensions: companion_ext
+                        .iter()
+                        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                        .collect(),
+                    cover_max_size,
+                    cover_quality,
+                },
+                cover: embed_cover.then_some(CoverArt {
+                    names: cover_names,
+                    max_size: embed_cover_max_size,
+                }),
+                lossy_source,
+                allow_upscale,
+                replaygain,
+                normalize: normalize.map(|target| Normalize { target, true_peak }),
+                conversion: Conversion {
+                    sample_rate,
+                    bit_depth,
+                    dither,
+                    downmix,
+                    matrix: downmix_matrix,
+                },
+                overwrite,
+            },
             qffmpeg,
         )?,
         Commands::TranscodeVideoAV1 {
@@ -182,6 +545,7 @@ fn main() -> Result<()> {
             crf,
             keyframe_interval,
             force_10bit,
+            profile,
             overwrite,
             qffmpeg,
         } => transcode_video::run(
@@ -191,6 +555,7 @@ fn main() -> Result<()> {
             crf,
             keyframe_interval,
             force_10bit,
+            profile.as_deref(),
             overwrite,
             qffmpeg,
         )?,
@@ -228,6 +593,24 @@ fn main() -> Result<()> {
             overwrite,
             qffmpeg,
         )?,
+        Commands::SplitAudio {
+            src_file,
+            detect_silence: Some(timestamps_file),
+            noise,
+            min_silence,
+            tracks,
+            overwrite,
+            ..
+        } => split_audio::silence::propose(
+            &src_file,
+            &timestamps_file,
+            &Detection {
+                noise,
+                min_duration: min_silence,
+                tracks,
+            },
+            overwrite,
+        )?,
         Commands::SplitAudio {
             src_file,
             dest_path,
@@ -235,19 +618,57 @@ fn main() -> Result<()> {
             artist,
             album,
             date,
+            from_chapters,
+            accurate,
             overwrite,
             qffmpeg,
+            ..
         } => split_audio::run(
             &src_file,
-            &dest_path,
-            &timestamps_file,
-            artist,
-            album,
-            date,
-            overwrite,
+            dest_path.as_deref().context("No destination path given!")?,
+            timestamps_file.as_deref(),
+            SplitOptions {
+                from_chapters,
+                accurate,
+                artist,
+                album,
+                date,
+                overwrite,
+            },
             qffmpeg,
         )?,
+        Commands::Profiles { command } => match command {
+            ProfilesCommand::List => profiles::list()?,
+            ProfilesCommand::Show { name } => profiles::show(&name)?,
+        },
+        Commands::Loudness { path, qffmpeg } => loudness::run(&path, qffmpeg)?,
+        Commands::Probe { path, json } => probe_media::run(&path, json)?,
     }
 
+    plan::finish()?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_negative_noise_levels() {
+        let cli = Cli::try_parse_from([
+            "mediatools",
+            "split-audio",
+            "album.flac",
+            "--detect-silence",
+            "timestamps.txt",
+            "--noise",
+            "-40dB",
+        ])
+        .unwrap();
+        let Commands::SplitAudio { noise, .. } = cli.command else {
+            panic!("expected split-audio, got {:?}", cli.command);
+        };
+        assert_eq!(noise, "-40dB");
+    }
+}
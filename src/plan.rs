@@ -0,0 +1,138 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use simplelog::info;
+
+use crate::{global_args, utils};
+
+/// How the plan of a dry run is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PlanFormat {
+    /// Log each action as it's planned.
+    Text,
+    /// Print all actions as a JSON array once the command is done.
+    Json,
+}
+
+/// Something a command would have done if it wasn't a dry run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Ffmpeg {
+        /// The full command, quoted so it can be pasted into a shell.
+        command: String,
+        output: PathBuf,
+    },
+    Skip {
+        path: PathBuf,
+        reason: String,
+    },
+    CreateDir {
+        path: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Delete {
+        path: PathBuf,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        /// How the file is copied, e.g. 'copy' or 'hardlink'.
+        method: String,
+    },
+}
+
+static ACTIONS: Mutex<Vec<Action>> = Mutex::new(Vec::new());
+static CREATED_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Whether this is a dry run, i.e. whether actions should be recorded instead of performed.
+pub fn is_dry_run() -> bool {
+    global_args().dry_run
+}
+
+pub fn record(action: Action) {
+    if global_args().plan_format == PlanFormat::Json {
+        ACTIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(action);
+        return;
+    }
+    match action {
+        Action::Ffmpeg { command, .. } => info!("Would run: {}", command),
+        Action::Skip { path, reason } => info!("Would skip {}: {}", path.display(), reason),
+        Action::CreateDir { path } => info!("Would create directory {}", path.display()),
+        Action::Rename { from, to } => {
+            info!("Would rename {} to {}", from.display(), to.display())
+        }
+        Action::Delete { path } => info!("Would delete {}", path.display()),
+        Action::Copy { from, to, method } => {
+            info!("Would {} {} to {}", method, from.display(), to.display())
+        }
+    }
+}
+
+/// Creates a directory and all of its parents, or records that it would be created in a dry run.
+pub fn create_dir_all(path: &Path) -> Result<()> {
+    if !is_dry_run() {
+        fs::create_dir_all(path)?;
+        return Ok(());
+    }
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Ok(());
+    }
+    let is_new = CREATED_DIRS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(path.to_path_buf());
+    if is_new {
+        record(Action::CreateDir {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+/// Renames a file, or records that it would be renamed in a dry run.
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+    if is_dry_run() {
+        record(Action::Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        });
+    } else {
+        fs::rename(from, to)?;
+    }
+    Ok(())
+}
+
+/// Prints the plan if it's being output as JSON. Must be called once the command is done.
+pub fn finish() -> Result<()> {
+    if is_dry_run() && global_args().plan_format == PlanFormat::Json {
+        let actions = ACTIONS.lock().unwrap_or_else(|e| e.into_inner());
+        println!("{}", serde_json::to_string_pretty(&*actions)?);
+    }
+    Ok(())
+}
+
+/// Quotes an FFmpeg command so it can be pasted into a shell.
+pub fn ffmpeg_command<I, S>(args: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    std::iter::once("ffmpeg".to_string())
+        .chain(args.into_iter().map(|a| utils::shell_quote(a.as_ref())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
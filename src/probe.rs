@@ -0,0 +1,262 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Everything ffprobe knows about a media file.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MediaInfo {
+    pub format: Format,
+    #[serde(default)]
+    pub streams: Vec<Stream>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+}
+
+impl MediaInfo {
+    /// Whether this is an audio file, i.e. it has audio and no video other than cover art.
+    pub fn is_audio(&self) -> bool {
+        self.streams
+            .iter()
+            .any(|s| s.codec_type == CodecType::Audio)
+            && !self
+                .streams
+                .iter()
+                .any(|s| s.codec_type == CodecType::Video && !s.disposition.attached_pic)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Format {
+    pub format_name: String,
+    pub format_long_name: Option<String>,
+    /// The duration of the whole file in seconds.
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub duration: Option<f64>,
+    /// The size of the file in bytes.
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub size: Option<u64>,
+    /// The overall bitrate in bits per second.
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub bit_rate: Option<u64>,
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecType {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+    Attachment,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Stream {
+    /// The index of the stream in the file, across all stream types.
+    pub index: usize,
+    pub codec_type: CodecType,
+    pub codec_name: Option<String>,
+    pub codec_long_name: Option<String>,
+    /// The duration of the stream in seconds.
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub duration: Option<f64>,
+    /// The bitrate of the stream in bits per second. Often missing for VBR streams.
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub bit_rate: Option<u64>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    pub sample_fmt: Option<String>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub bits_per_raw_sample: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pix_fmt: Option<String>,
+    #[serde(default)]
+    pub disposition: Disposition,
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+impl Stream {
+    pub fn language(&self) -> Option<&str> {
+        self.tags.get("language")
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.tags.get("title")
+    }
+}
+
+/// The flags of a stream. Only the ones the tools care about are included.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Disposition {
+    #[serde(deserialize_with = "bool_from_int")]
+    pub default: bool,
+    #[serde(deserialize_with = "bool_from_int")]
+    pub forced: bool,
+    #[serde(rename = "comment", deserialize_with = "bool_from_int")]
+    pub commentary: bool,
+    #[serde(deserialize_with = "bool_from_int")]
+    pub hearing_impaired: bool,
+    #[serde(deserialize_with = "bool_from_int")]
+    pub visual_impaired: bool,
+    #[serde(deserialize_with = "bool_from_int")]
+    pub attached_pic: bool,
+}
+
+impl Disposition {
+    /// The names of all set flags.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.default, "default"),
+            (self.forced, "forced"),
+            (self.commentary, "commentary"),
+            (self.hearing_impaired, "hearing impaired"),
+            (self.visual_impaired, "visual impaired"),
+            (self.attached_pic, "attached pic"),
+        ]
+        .into_iter()
+        .filter_map(|(set, name)| set.then_some(name))
+        .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Chapter {
+    pub id: i64,
+    /// The start of the chapter in seconds.
+    #[serde(deserialize_with = "from_str")]
+    pub start_time: f64,
+    /// The end of the chapter in seconds.
+    #[serde(deserialize_with = "from_str")]
+    pub end_time: f64,
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+impl Chapter {
+    pub fn title(&self) -> Option<&str> {
+        self.tags.get("title")
+    }
+}
+
+/// Metadata tags. Containers don't agree on the case of tag names ('title' vs 'TITLE'), so
+/// lookups ignore it.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Tags(pub BTreeMap<String, String>);
+
+impl Tags {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Runs ffprobe on a file and parses everything it outputs.
+pub fn probe(path: &Path) -> Result<MediaInfo> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            "-show_chapters",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| "Failed to run ffprobe! Make sure it's installed and on your PATH.")?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe failed to read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse ffprobe's output for {}", path.display()))
+}
+
+/// Gets the duration of a media file. Cheaper than a full [`probe`].
+pub fn duration(path: &Path) -> Result<Duration> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| "Failed to run ffprobe! Make sure it's installed and on your PATH.")?;
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .with_context(|| format!("Failed to get the duration of {}", path.display()))
+}
+
+/// ffprobe writes most numbers as strings, and uses 'N/A' when it doesn't know them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StrOrNum<T> {
+    Num(T),
+    Str(String),
+}
+
+impl<T: FromStr> StrOrNum<T> {
+    fn parse(self) -> Option<T> {
+        match self {
+            Self::Num(n) => Some(n),
+            Self::Str(s) => s.parse().ok(),
+        }
+    }
+}
+
+fn from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+{
+    Ok(Option::<StrOrNum<T>>::deserialize(deserializer)?.and_then(StrOrNum::parse))
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + Deserialize<'de>,
+{
+    StrOrNum::deserialize(deserializer)?
+        .parse()
+        .ok_or_else(|| serde::de::Error::custom("invalid number"))
+}
+
+fn bool_from_int<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(i64::deserialize(deserializer)? != 0)
+}
@@ -0,0 +1,161 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{LevelFilter, Log, Metadata, Record};
+
+static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+fn multi() -> &'static MultiProgress {
+    MULTI_PROGRESS.get_or_init(MultiProgress::new)
+}
+
+/// Wraps another logger so log lines are printed above the progress bars instead of through them.
+struct ProgressLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for ProgressLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        multi().suspend(|| self.inner.log(record));
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs `logger` as the global logger, making it play nicely with the progress bars.
+pub fn init_logger(logger: Box<dyn Log>, level: LevelFilter) -> Result<()> {
+    log::set_boxed_logger(Box::new(ProgressLogger { inner: logger }))
+        .with_context(|| "Failed to initialize logger!")?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// The fields we care about from FFmpeg's `-progress` output.
+#[derive(Debug, Default)]
+pub struct FfmpegProgress {
+    /// How much of the output has been written so far.
+    pub out_time: Option<Duration>,
+    /// How many times faster than realtime FFmpeg is encoding.
+    pub speed: Option<f64>,
+    pub fps: Option<f64>,
+}
+
+impl FfmpegProgress {
+    /// Updates the progress from a single `key=value` line of FFmpeg's `-progress` output.
+    /// Returns whether the line ended a block, i.e. whether the progress should be reported.
+    pub fn update(&mut self, line: &str) -> bool {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            return false;
+        };
+        match key {
+            "out_time_us" => self.out_time = value.parse().ok().map(Duration::from_micros),
+            "speed" => self.speed = value.trim().trim_end_matches('x').parse().ok(),
+            "fps" => self.fps = value.parse().ok().filter(|fps| *fps > 0.0),
+            "progress" => return true,
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Progress bars for a whole batch of jobs. Each job gets its own bar while it's running, and
+/// the overall bar tracks how much of the batch's total duration has been encoded.
+pub struct Progress {
+    overall: ProgressBar,
+    jobs: usize,
+    finished_jobs: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(total_duration: Duration, jobs: usize) -> Self {
+        let overall = multi().add(ProgressBar::new(total_duration.as_millis() as u64));
+        overall.set_style(
+            ProgressStyle::with_template(
+                "{prefix:.bold.green} [{bar:30.green}] {percent:>3}% | ETA {eta} | {msg}",
+            )
+            .expect("invalid progress template")
+            .progress_chars("=> "),
+        );
+        overall.set_prefix("Overall");
+        overall.set_message(format!("0/{} files", jobs));
+        Self {
+            overall,
+            jobs,
+            finished_jobs: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn start_job(&self, name: &str, duration: Option<Duration>) -> JobProgress<'_> {
+        let bar = multi().add(ProgressBar::new(
+            duration.map(|d| d.as_millis() as u64).unwrap_or_default(),
+        ));
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{prefix:.bold} [{bar:30.cyan/blue}] {percent:>3}% | ETA {eta} | {msg}",
+            )
+            .expect("invalid progress template")
+            .progress_chars("=> "),
+        );
+        bar.set_prefix(name.to_string());
+        JobProgress {
+            bar,
+            progress: self,
+        }
+    }
+
+    pub fn finish(&self) {
+        self.overall.finish_and_clear();
+    }
+}
+
+/// The progress bar of a single running job.
+pub struct JobProgress<'a> {
+    bar: ProgressBar,
+    progress: &'a Progress,
+}
+
+impl JobProgress<'_> {
+    pub fn update(&self, ffmpeg: &FfmpegProgress) {
+        if let Some(out_time) = ffmpeg.out_time {
+            let pos = (out_time.as_millis() as u64).min(self.bar.length().unwrap_or_default());
+            self.progress
+                .overall
+                .inc(pos.saturating_sub(self.bar.position()));
+            self.bar.set_position(pos);
+        }
+
+        let mut msg = Vec::new();
+        if let Some(speed) = ffmpeg.speed {
+            msg.push(format!("{:.2}x", speed));
+        }
+        if let Some(fps) = ffmpeg.fps {
+            msg.push(format!("{:.1} fps", fps));
+        }
+        self.bar.set_message(msg.join(", "));
+    }
+
+    pub fn finish(self) {
+        // Count whatever FFmpeg didn't report (or the whole job, if it failed) as done so the
+        // overall bar still reaches the end.
+        let remaining = self.bar.length().unwrap_or_default() - self.bar.position();
+        self.progress.overall.inc(remaining);
+        let finished = self.progress.finished_jobs.fetch_add(1, Ordering::Relaxed) + 1;
+        self.progress
+            .overall
+            .set_message(format!("{}/{} files", finished, self.progress.jobs));
+        self.bar.finish_and_clear();
+    }
+}
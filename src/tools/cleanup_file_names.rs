@@ -4,6 +4,8 @@ use anyhow::{Context, Result};
 use lazy_regex::{lazy_regex, Lazy, Regex};
 use log::info;
 
+use crate::plan;
+
 static REMOVE_PATTERN: Lazy<Regex> = lazy_regex!(r"\s*\[[^\]]*\]");
 
 pub fn run(path: PathBuf) -> Result<()> {
@@ -27,7 +29,7 @@ pub fn run(path: PathBuf) -> Result<()> {
             }
 
             let new_path = entry_path.with_file_name(&new_name);
-            fs::rename(&entry_path, &new_path).with_context(|| {
+            plan::rename(&entry_path, &new_path).with_context(|| {
                 format!(
                     "Failed to rename: {} to {}",
                     entry_path.display(),
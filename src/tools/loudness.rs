@@ -0,0 +1,311 @@
+use anyhow::{Context, Result};
+use lazy_regex::{lazy_regex, Lazy, Regex};
+use simplelog::info;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    global_args,
+    jobs::{self, Job, JobQueue},
+    path_to_str,
+    plan::{self, Action},
+    probe::{self, CodecType},
+    utils,
+};
+
+/// The loudness ReplayGain 2.0 gains are relative to, in LUFS.
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// The loudness Opus' R128 gains are relative to, in LUFS.
+const R128_REFERENCE: f64 = -23.0;
+
+/// The extensions of the files that are measured when given a directory.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "flac", "mp3", "m4a", "m4b", "mp4", "aac", "ogg", "oga", "opus", "wv", "mka", "wav", "aiff",
+    "aif",
+];
+
+static INTEGRATED_PATTERN: Lazy<Regex> = lazy_regex!(r"I:\s+(-?[\d.]+|-inf) LUFS");
+static TRUE_PEAK_PATTERN: Lazy<Regex> = lazy_regex!(r"Peak:\s+(-?[\d.]+|-inf) dBFS");
+
+/// The loudness of a track, as measured by FFmpeg's ebur128 filter.
+struct Measurement {
+    /// The integrated loudness in LUFS.
+    loudness: f64,
+    /// The true peak in dBTP.
+    peak: f64,
+    /// The duration in seconds, to weigh the track by when working out the album's loudness.
+    duration: f64,
+    /// Whether the track is Opus, which uses its own gain tags.
+    is_opus: bool,
+}
+
+/// Measures the loudness of every audio file in `path` and tags them with their track and album
+/// gain. Each directory is treated as an album, so discs in their own directories get their own
+/// album gain.
+pub fn run(path: &Path, qffmpeg: bool) -> Result<()> {
+    let files = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        utils::walk_dir(path, &global_args().walk, |p| is_audio(p))?
+    };
+    let mut albums = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for file in files {
+        let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+        albums.entry(dir).or_default().push(file);
+    }
+    tag_albums(albums.into_values().collect(), qffmpeg)
+}
+
+/// Tags every file in a directory with its track and album gain. Used after transcoding, so
+/// `extension` limits it to the files that were transcoded.
+pub fn tag_dirs(dirs: &[PathBuf], extension: &str, qffmpeg: bool) -> Result<()> {
+    if plan::is_dry_run() {
+        info!(
+            "Would measure the loudness of {} album(s) once they're transcoded.",
+            dirs.len()
+        );
+        return Ok(());
+    }
+    let mut albums = Vec::new();
+    for dir in dirs {
+        let mut files = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.is_file()
+                    && p.extension()
+                        .is_some_and(|e| e.to_string_lossy().eq_ignore_ascii_case(extension))
+            })
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        albums.push(files);
+    }
+    tag_albums(albums, qffmpeg)
+}
+
+fn tag_albums(albums: Vec<Vec<PathBuf>>, qffmpeg: bool) -> Result<()> {
+    let files = albums.iter().flatten().collect::<Vec<_>>();
+    let mut queue = JobQueue::new(qffmpeg);
+    // Measuring decodes every file, which is too much for a dry run.
+    if plan::is_dry_run() {
+        info!("Not measuring loudness in a dry run, so 'MEASURED' stands in for the gains.");
+        for path in files {
+            match placeholder_tags(path) {
+                Ok(tags) => queue.push(tag_job(path, tags)?),
+                Err(e) => queue.skip(path, format!("{:#}", e)),
+            }
+        }
+        return queue.run();
+    }
+
+    info!("Measuring the loudness of {} file(s)...", files.len());
+    let measurements = jobs::parallel_map(&files, |f| measure(f));
+
+    let mut measurements = files.into_iter().zip(measurements);
+    for album in &albums {
+        let tracks = measurements.by_ref().take(album.len()).collect::<Vec<_>>();
+        let measured = tracks
+            .iter()
+            .filter_map(|(_, m)| m.as_ref().ok())
+            .collect::<Vec<_>>();
+        let album_loudness = album_loudness(&measured);
+        let album_peak = measured
+            .iter()
+            .map(|m| m.peak)
+            .fold(f64::NEG_INFINITY, f64::max);
+
+        for (path, measurement) in tracks {
+            match measurement {
+                Ok(m) => queue.push(tag_job(path, gain_tags(&m, album_loudness, album_peak))?),
+                Err(e) => queue.skip(path, format!("{:#}", e)),
+            }
+        }
+    }
+    queue.run()
+}
+
+fn measure(path: &Path) -> Result<Measurement> {
+    let info = probe::probe(path)?;
+    let stream = info
+        .streams
+        .iter()
+        .find(|s| s.codec_type == CodecType::Audio)
+        .context("It has no audio stream")?;
+
+    let output = utils::ffmpeg_output([
+        "-i",
+        path_to_str!(path)?,
+        "-map",
+        "0:a:0",
+        "-filter:a",
+        "ebur128=peak=true",
+        "-f",
+        "null",
+        "-",
+    ])
+    .context("Failed to measure its loudness")?;
+    // The summary comes last, after the values of each frame.
+    let last_value = |pattern: &Regex| {
+        pattern
+            .captures_iter(&output)
+            .last()
+            .and_then(|c| c[1].parse::<f64>().ok())
+    };
+
+    Ok(Measurement {
+        loudness: last_value(&INTEGRATED_PATTERN).context("FFmpeg didn't report the loudness")?,
+        peak: last_value(&TRUE_PEAK_PATTERN).context("FFmpeg didn't report the true peak")?,
+        duration: info.format.duration.or(stream.duration).unwrap_or(1.0),
+        is_opus: stream.codec_name.as_deref() == Some("opus"),
+    })
+}
+
+/// Works out the loudness of an album from the loudness of its tracks. This is the energy average
+/// weighted by duration, which is close to measuring all tracks in one go without decoding them
+/// again.
+fn album_loudness(tracks: &[&Measurement]) -> f64 {
+    let total_duration = tracks.iter().map(|m| m.duration).sum::<f64>();
+    let energy = tracks
+        .iter()
+        .filter(|m| m.loudness.is_finite())
+        .map(|m| m.duration * 10f64.powf(m.loudness / 10.0))
+        .sum::<f64>();
+    10.0 * (energy / total_duration).log10()
+}
+
+/// Gets the gain tags of a track.
+fn gain_tags(
+    track: &Measurement,
+    album_loudness: f64,
+    album_peak: f64,
+) -> Vec<(&'static str, String)> {
+    if track.is_opus {
+        vec![
+            ("R128_TRACK_GAIN", r128_gain(track.loudness)),
+            ("R128_ALBUM_GAIN", r128_gain(album_loudness)),
+        ]
+    } else {
+        vec![
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                format!("{:.2} dB", gain(REPLAYGAIN_REFERENCE, track.loudness)),
+            ),
+            (
+                "REPLAYGAIN_TRACK_PEAK",
+                format!("{:.6}", linear_peak(track.peak)),
+            ),
+            (
+                "REPLAYGAIN_ALBUM_GAIN",
+                format!("{:.2} dB", gain(REPLAYGAIN_REFERENCE, album_loudness)),
+            ),
+            (
+                "REPLAYGAIN_ALBUM_PEAK",
+                format!("{:.6}", linear_peak(album_peak)),
+            ),
+        ]
+    }
+}
+
+/// Gets the gain tags a track would get, with 'MEASURED' in place of the values, for a dry run.
+fn placeholder_tags(path: &Path) -> Result<Vec<(&'static str, String)>> {
+    let info = probe::probe(path)?;
+    let stream = info
+        .streams
+        .iter()
+        .find(|s| s.codec_type == CodecType::Audio)
+        .context("It has no audio stream")?;
+    let track = Measurement {
+        loudness: 0.0,
+        peak: 0.0,
+        duration: 0.0,
+        is_opus: stream.codec_name.as_deref() == Some("opus"),
+    };
+    Ok(gain_tags(&track, 0.0, 0.0)
+        .into_iter()
+        .map(|(key, _)| (key, "MEASURED".to_string()))
+        .collect())
+}
+
+/// Makes a job that writes the gain tags by remuxing the file into a temporary file next to it,
+/// which then replaces it.
+fn tag_job(path: &Path, tags: Vec<(&str, String)>) -> Result<Job> {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    let tmp_path = path.with_file_name(format!(
+        ".{}.loudness-tmp.{}",
+        path.file_stem().unwrap_or_default().to_string_lossy(),
+        extension
+    ));
+
+    let mut args = vec![
+        "-y".to_string(),
+        "-i".to_string(),
+        path_to_str!(path)?.to_string(),
+        "-map".to_string(),
+        "0".to_string(),
+        "-c".to_string(),
+        "copy".to_string(),
+        "-map_metadata".to_string(),
+        "0".to_string(),
+    ];
+    // Ogg keeps its tags with the stream instead of the file.
+    let metadata_option = match extension.as_str() {
+        "ogg" | "oga" | "opus" => "-metadata:s:a:0",
+        _ => "-metadata",
+    };
+    for (key, value) in tags {
+        args.push(metadata_option.to_string());
+        args.push(format!("{}={}", key, value));
+    }
+    match extension.as_str() {
+        "mp3" => args.extend(["-id3v2_version".to_string(), "3".to_string()]),
+        // MP4 only writes tags it knows about without this.
+        "m4a" | "m4b" | "mp4" => args.extend(["-movflags".to_string(), "use_metadata_tags".into()]),
+        _ => {}
+    }
+    args.push(path_to_str!(tmp_path)?.to_string());
+
+    let (from, to) = (tmp_path.clone(), path.to_path_buf());
+    Ok(Job::new(path.display().to_string(), &tmp_path, args)
+        .plans(Action::Rename {
+            from: tmp_path.clone(),
+            to: path.to_path_buf(),
+        })
+        .then(move || {
+            fs::rename(&from, &to)
+                .with_context(|| format!("Failed to replace {} with the tagged file", to.display()))
+        })
+        // Don't leave a partial file next to the original if anything went wrong.
+        .finally(move || {
+            let _ = fs::remove_file(&tmp_path);
+        }))
+}
+
+fn gain(reference: f64, loudness: f64) -> f64 {
+    if loudness.is_finite() {
+        reference - loudness
+    } else {
+        0.0
+    }
+}
+
+/// Opus gains are Q7.8 fixed point numbers.
+fn r128_gain(loudness: f64) -> String {
+    let gain = (gain(R128_REFERENCE, loudness) * 256.0).round();
+    (gain.clamp(i16::MIN.into(), i16::MAX.into()) as i16).to_string()
+}
+
+fn linear_peak(dbtp: f64) -> f64 {
+    10f64.powf(dbtp / 20.0)
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str()))
+}
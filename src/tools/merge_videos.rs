@@ -1,10 +1,15 @@
-use std::{fs, path::Path};
+use std::path::Path;
 
 use anyhow::{Context, Result};
 use simplelog::{error, info};
 
-use crate::{path_to_str, utils};
+use crate::{
+    global_args,
+    jobs::{Job, JobQueue},
+    path_to_str, utils,
+};
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     base_path: &Path,
     content_path: &Path,
@@ -15,14 +20,16 @@ pub fn run(
     overwrite: bool,
     qffmpeg: bool,
 ) -> Result<()> {
-    let base_files = utils::read_dir(base_path, |p| p.is_file())?;
-    let content_files = utils::read_dir(content_path, |p| p.is_file())?;
+    let base_files = utils::walk_dir(base_path, &global_args().walk, |p| p.is_file())?;
+    let content_files = utils::walk_dir(content_path, &global_args().walk, |p| p.is_file())?;
 
     if base_files.len() != content_files.len() {
         error!("The base directory has {} files, but the stream directory has {} files! There must be the same amount of files in both directories.", base_files.len(), content_files.len());
         return Ok(());
     }
 
+    let mut queue = JobQueue::new(qffmpeg);
+
     for i in 0..base_files.len() {
         let base_file = &base_files[i];
         let content_file = &content_files[i];
@@ -33,14 +40,12 @@ pub fn run(
             content_file.file_name().unwrap_or_default()
         );
 
-        fs::create_dir_all(dest_path)?;
-
-        let file_name_to_copy = if use_content_names {
-            content_file
+        let rel_path = if use_content_names {
+            content_file.strip_prefix(content_path)?
         } else {
-            base_file
+            base_file.strip_prefix(base_path)?
         };
-        let dest_file = dest_path.join(file_name_to_copy.file_name().context("No file name?")?);
+        let dest_file = dest_path.join(rel_path);
 
         let mut args = vec![
             if overwrite { "-y" } else { "-n" },
@@ -85,8 +90,12 @@ pub fn run(
         args.push("copy");
         args.push(path_to_str!(dest_file)?);
 
-        utils::run_ffmpeg(qffmpeg, args)?;
+        queue.push(Job::new(
+            base_file.file_name().unwrap_or_default().to_string_lossy(),
+            &dest_file,
+            args,
+        ));
     }
 
-    Ok(())
+    queue.run()
 }
@@ -1,5 +1,8 @@
 pub mod cleanup_file_names;
+pub mod loudness;
 pub mod merge_videos;
+pub mod probe_media;
+pub mod profiles;
 pub mod set_default_tracks;
 pub mod split_audio;
 pub mod transcode_audio;
@@ -0,0 +1,154 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{
+    probe::{self, CodecType, MediaInfo, Stream},
+    utils,
+};
+
+pub fn run(path: &Path, json: bool) -> Result<()> {
+    let info = probe::probe(path)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    print_format(path, &info);
+    print_streams(&info);
+    print_chapters(&info);
+    Ok(())
+}
+
+fn print_format(path: &Path, info: &MediaInfo) {
+    let format = &info.format;
+    println!("File: {}", path.display());
+    println!(
+        "Format: {}",
+        format
+            .format_long_name
+            .as_deref()
+            .unwrap_or(&format.format_name)
+    );
+    if let Some(duration) = format.duration {
+        println!("Duration: {}", format_seconds(duration));
+    }
+    if let Some(size) = format.size {
+        println!("Size: {:.1} MiB", size as f64 / 1024.0 / 1024.0);
+    }
+    if let Some(bit_rate) = format.bit_rate {
+        println!("Bitrate: {} kb/s", bit_rate / 1000);
+    }
+    if !format.tags.0.is_empty() {
+        println!("Tags:");
+        for (key, value) in &format.tags.0 {
+            println!("  {}: {}", key, value);
+        }
+    }
+}
+
+fn print_streams(info: &MediaInfo) {
+    if info.streams.is_empty() {
+        return;
+    }
+    let rows = info
+        .streams
+        .iter()
+        .map(|s| {
+            vec![
+                s.index.to_string(),
+                format!("{:?}", s.codec_type).to_lowercase(),
+                s.codec_name.clone().unwrap_or_default(),
+                s.language().unwrap_or_default().to_string(),
+                s.title().unwrap_or_default().to_string(),
+                stream_details(s),
+                s.disposition.names().join(", "),
+            ]
+        })
+        .collect::<Vec<_>>();
+    println!();
+    println!("Streams:");
+    for line in utils::format_table(
+        &[
+            "#",
+            "Type",
+            "Codec",
+            "Language",
+            "Title",
+            "Details",
+            "Disposition",
+        ],
+        &rows,
+    ) {
+        println!("  {}", line);
+    }
+}
+
+fn print_chapters(info: &MediaInfo) {
+    if info.chapters.is_empty() {
+        return;
+    }
+    let rows = info
+        .chapters
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            vec![
+                (i + 1).to_string(),
+                format_seconds(c.start_time),
+                format_seconds(c.end_time),
+                c.title().unwrap_or_default().to_string(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    println!();
+    println!("Chapters:");
+    for line in utils::format_table(&["#", "Start", "End", "Title"], &rows) {
+        println!("  {}", line);
+    }
+}
+
+/// The most interesting properties of a stream, depending on its type.
+fn stream_details(stream: &Stream) -> String {
+    let mut details = Vec::new();
+    match stream.codec_type {
+        CodecType::Video => {
+            if let (Some(width), Some(height)) = (stream.width, stream.height) {
+                details.push(format!("{}x{}", width, height));
+            }
+            details.extend(stream.pix_fmt.clone());
+        }
+        CodecType::Audio => {
+            if let Some(sample_rate) = stream.sample_rate {
+                details.push(format!("{} Hz", sample_rate));
+            }
+            if let Some(bits) = stream.bits_per_raw_sample {
+                details.push(format!("{}-bit", bits));
+            }
+            details.extend(
+                stream
+                    .channel_layout
+                    .clone()
+                    .or(stream.channels.map(|c| format!("{}ch", c))),
+            );
+        }
+        _ => {}
+    }
+    if let Some(bit_rate) = stream.bit_rate {
+        details.push(format!("{} kb/s", bit_rate / 1000));
+    }
+    details.join(", ")
+}
+
+/// Formats seconds as 'H:MM:SS.mmm'.
+fn format_seconds(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
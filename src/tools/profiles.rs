@@ -0,0 +1,47 @@
+use anyhow::Result;
+use simplelog::info;
+
+use crate::{
+    config::{self, Config},
+    utils,
+};
+
+pub fn list() -> Result<()> {
+    let config = config::load()?;
+    if config.profiles.is_empty() {
+        info!(
+            "No profiles found. Add them to {} as [profiles.<name>] tables.",
+            config::default_path()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|| "the config file".to_string())
+        );
+        return Ok(());
+    }
+
+    let rows = config
+        .profiles
+        .iter()
+        .map(|(name, profile)| {
+            vec![
+                name.clone(),
+                profile.encoder.clone().unwrap_or_default(),
+                profile.bitrate.clone().unwrap_or_default(),
+                profile.container.clone().unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    for line in utils::format_table(&["Name", "Encoder", "Bitrate", "Container"], &rows) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+pub fn show(name: &str) -> Result<()> {
+    let profile = config::load_profile(Some(name))?;
+    // Wrapped in a config so the output can be pasted straight into the config file.
+    let config = Config {
+        profiles: [(name.to_string(), profile)].into(),
+    };
+    print!("{}", toml::to_string_pretty(&config)?);
+    Ok(())
+}
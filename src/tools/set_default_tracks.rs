@@ -1,7 +1,11 @@
 use anyhow::{Context, Result};
-use std::{fs, path::Path};
+use std::path::Path;
 
-use crate::{path_to_str, utils};
+use crate::{
+    global_args,
+    jobs::{Job, JobQueue},
+    path_to_str, utils,
+};
 
 pub fn run(
     src_path: &Path,
@@ -11,9 +15,9 @@ pub fn run(
     overwrite: bool,
     qffmpeg: bool,
 ) -> Result<()> {
-    fs::create_dir_all(dest_path)?;
+    let mut queue = JobQueue::new(qffmpeg);
 
-    for path in utils::read_dir(src_path, |p| {
+    for path in utils::walk_dir(src_path, &global_args().walk, |p| {
         p.is_file()
             && p.extension()
                 .map(|e| e.to_string_lossy().to_lowercase())
@@ -45,8 +49,8 @@ pub fn run(
             path_to_str!(out_path)?,
         ];
 
-        utils::run_ffmpeg(qffmpeg, args)?;
+        queue.push(Job::new(rel_path.display().to_string(), &out_path, args));
     }
 
-    Ok(())
+    queue.run()
 }
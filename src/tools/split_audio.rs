@@ -1,140 +1,294 @@
-use std::{fs, path::Path};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
-use lazy_regex::{lazy_regex, Lazy, Regex};
 
-use crate::{path_to_str, utils};
+use crate::{
+    jobs::{Job, JobQueue},
+    path_to_str, plan, probe,
+};
 
-// CD | Track | Title | Start Time
-// Examples:
-// 1 1 Break Out - Version 1 0:00
-// 4 1 Prove It All Night - Version 1 2:41:38
-static TIMESTAMP_PATTERN: Lazy<Regex> =
-    lazy_regex!(r"([0-9*]*) ([0-9]*) (.*) ([0-9]{1,2}:[0-9]{1,2}:?[0-9]{1,2})");
+use self::accurate::Cutter;
 
+mod accurate;
+mod cue;
+pub mod silence;
+mod timestamps;
+mod validate;
+
+/// Formats FFmpeg can't write, so tracks cut from them are encoded to FLAC instead of copied.
+const NO_MUXER: &[&str] = &["ape", "tak"];
+
+/// Metadata shared by every track.
+#[derive(Debug, Default)]
+struct Album {
+    artist: Option<String>,
+    title: Option<String>,
+    date: Option<String>,
+    genre: Option<String>,
+}
+
+/// A track to cut out of a source file.
+#[derive(Debug, Default)]
+struct Track {
+    src: PathBuf,
+    disc: usize,
+    number: usize,
+    title: String,
+    /// The track's own artist, if it differs from the album's.
+    artist: Option<String>,
+    composer: Option<String>,
+    /// The track's own genre, if it differs from the album's.
+    genre: Option<String>,
+    start: Duration,
+    /// Where the track ends, or `None` if it runs to the end of the source.
+    end: Option<Duration>,
+}
+
+/// How a file is split, as given on the command line.
+pub struct SplitOptions {
+    /// Split at the source's chapters. A timestamps file then only overrides their titles.
+    pub from_chapters: bool,
+    /// Cut tracks on the exact sample instead of between packets.
+    pub accurate: bool,
+    /// Overrides any artist from the cue sheet or source.
+    pub artist: Option<String>,
+    /// Overrides any album from the cue sheet or source.
+    pub album: Option<String>,
+    /// Overrides any date from the cue sheet or source.
+    pub date: Option<String>,
+    pub overwrite: bool,
+}
+
+/// Splits a file into tracks. The tracks are read from `timestamps_file`, which is either a cue
+/// sheet (by its '.cue' extension) or a list of timestamps, or from the source's chapters.
 pub fn run(
     src_file: &Path,
     dest_path: &Path,
-    timestamps_file: &Path,
-    artist: Option<String>,
-    album: Option<String>,
-    date: Option<String>,
-    overwrite: bool,
+    timestamps_file: Option<&Path>,
+    options: SplitOptions,
     qffmpeg: bool,
 ) -> Result<()> {
-    fs::create_dir_all(dest_path)?;
+    let SplitOptions {
+        from_chapters,
+        accurate,
+        artist,
+        album,
+        date,
+        overwrite,
+    } = options;
+    let (src_album, tracks) = match timestamps_file {
+        _ if from_chapters => {
+            let (album, mut tracks) = read_chapters(src_file)?;
+            if let Some(timestamps_file) = timestamps_file {
+                let (_, overrides) = read_tracks(timestamps_file, src_file)?;
+                override_titles(&mut tracks, overrides);
+            }
+            (album, tracks)
+        }
+        Some(timestamps_file) => read_tracks(timestamps_file, src_file)?,
+        None => bail!("No timestamps file given! Pass one or use '--from-chapters'."),
+    };
+    let album = Album {
+        artist: artist.or(src_album.artist),
+        title: album.or(src_album.title),
+        date: date.or(src_album.date),
+        genre: src_album.genre,
+    };
 
-    let ext = src_file
-        .extension()
-        .context("No extension on source file!")?
-        .to_string_lossy();
-
-    let raw_timestamps = fs::read_to_string(timestamps_file)?;
-    let timestamps = raw_timestamps
-        .lines()
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty())
-        .map(parse_timestamp)
-        .collect::<Result<Vec<Timestamp>>>()?;
-
-    let is_one_disc = timestamps.iter().all(|t| t.disc == 1);
-
-    let meta_artist = artist.as_ref().map(|a| format!("artist={}", a));
-    let meta_album_artist = artist.as_ref().map(|a| format!("albumartist={}", a));
-    let meta_album = album.as_ref().map(|a| format!("album={}", a));
-    let meta_date = date.as_ref().map(|a| format!("date={}", a));
-
-    for i in 0..timestamps.len() {
-        let stamp = &timestamps[i];
-
-        let file_name = &format!("{:02}. {}.{}", stamp.track, stamp.title, ext);
-
-        let mut args = Vec::new();
-        args.push(if overwrite { "-y" } else { "-n" });
-        args.push("-i");
-        args.push(path_to_str!(src_file)?);
-
-        args.push("-ss");
-        args.push(stamp.start_time);
-        if i + 1 < timestamps.len() {
-            args.push("-to");
-            args.push(timestamps[i + 1].start_time);
+    let is_one_disc = tracks.iter().all(|t| t.disc == 1);
+
+    // Without a duration, the times can't be checked and the progress of the last track of each
+    // source isn't known, but the tracks can still be cut.
+    let mut src_durations = BTreeMap::new();
+    let mut cutters = BTreeMap::new();
+    for track in &tracks {
+        if !src_durations.contains_key(&track.src) {
+            src_durations.insert(track.src.clone(), probe::duration(&track.src).ok());
+            if accurate {
+                cutters.insert(track.src.clone(), Cutter::new(&track.src)?);
+            }
         }
+    }
+
+    let file_names = tracks
+        .iter()
+        .map(|track| file_name(track, cutters.get(&track.src)))
+        .collect::<Result<Vec<_>>>()?;
+    validate::check(&tracks, &src_durations, &file_names)?;
 
-        args.push("-write_id3v2");
-        args.push("1");
+    plan::create_dir_all(dest_path)?;
+    let mut queue = JobQueue::new(qffmpeg);
+    for (track, file_name) in tracks.iter().zip(file_names) {
+        let cutter = cutters.get(&track.src);
+        let reencode = cutter.is_none() && needs_reencode(track)?;
 
-        if let Some(ref artist) = meta_artist {
-            args.push("-metadata");
-            args.push(artist);
+        let mut args = vec![if overwrite { "-y" } else { "-n" }.to_string()];
+        if let Some(cutter) = cutter {
+            args.extend(cutter.input_args(track.start));
         }
-        if let Some(ref album_artist) = meta_album_artist {
-            args.push("-metadata");
-            args.push(album_artist);
+        args.extend(["-i".to_string(), path_to_str!(track.src)?.to_string()]);
+        if cutter.is_none() {
+            args.extend(["-ss".to_string(), format_time(track.start)]);
+            if let Some(end) = track.end {
+                args.extend(["-to".to_string(), format_time(end)]);
+            }
         }
-        if let Some(ref album) = meta_album {
-            args.push("-metadata");
-            args.push(album);
+        let duration = track
+            .end
+            .or(src_durations[&track.src])
+            .map(|end| end.saturating_sub(track.start));
+
+        args.extend(["-write_id3v2".to_string(), "1".to_string()]);
+
+        let mut metadata = Vec::new();
+        if let Some(artist) = track.artist.as_ref().or(album.artist.as_ref()) {
+            metadata.push(format!("artist={}", artist));
         }
-        if let Some(ref date) = meta_date {
-            args.push("-metadata");
-            args.push(date);
+        if let Some(artist) = &album.artist {
+            metadata.push(format!("albumartist={}", artist));
+        }
+        if let Some(title) = &album.title {
+            metadata.push(format!("album={}", title));
+        }
+        if let Some(date) = &album.date {
+            metadata.push(format!("date={}", date));
+        }
+        if let Some(composer) = &track.composer {
+            metadata.push(format!("composer={}", composer));
+        }
+        if let Some(genre) = track.genre.as_ref().or(album.genre.as_ref()) {
+            metadata.push(format!("genre={}", genre));
+        }
+        metadata.push(format!("title={}", track.title));
+        metadata.push(format!("disc={}", track.disc));
+        metadata.push(format!("track={}", track.number));
+        for entry in metadata {
+            args.extend(["-metadata".to_string(), entry]);
         }
 
-        let meta_title = &format!("title={}", stamp.title);
-        args.push("-metadata");
-        args.push(meta_title);
-        let meta_disc = &format!("disc={}", stamp.disc);
-        args.push("-metadata");
-        args.push(meta_disc);
-        let meta_track = &format!("track={}", stamp.track);
-        args.push("-metadata");
-        args.push(meta_track);
-
-        args.push("-c");
-        args.push("copy");
+        // The source's chapters would be wrong in every track.
+        args.extend(["-map_chapters".to_string(), "-1".to_string()]);
+        if let Some(cutter) = cutter {
+            args.extend(cutter.args(track.start, track.end));
+        } else if reencode {
+            args.extend(["-c:a", "flac", "-c:v", "copy"].map(String::from));
+        } else {
+            args.extend(["-c".to_string(), "copy".to_string()]);
+        }
 
         let out_file = if is_one_disc {
-            dest_path.join(file_name)
+            dest_path.join(&file_name)
         } else {
-            let disc_dir = dest_path.join(format!("CD{}", stamp.disc));
-            fs::create_dir_all(&disc_dir)?;
-            disc_dir.join(file_name)
+            dest_path.join(format!("CD{}", track.disc)).join(&file_name)
         };
-        args.push(path_to_str!(out_file)?);
+        args.push(path_to_str!(out_file)?.to_string());
 
-        utils::run_ffmpeg(qffmpeg, args)?;
+        queue.push(Job::new(file_name, &out_file, args).with_duration(duration));
     }
 
-    Ok(())
+    queue.run()
+}
+
+/// Whether a track has to be encoded instead of copied, because FFmpeg can't write its format.
+fn needs_reencode(track: &Track) -> Result<bool> {
+    let ext = track
+        .src
+        .extension()
+        .context("No extension on source file!")?
+        .to_string_lossy()
+        .to_lowercase();
+    Ok(NO_MUXER.contains(&ext.as_str()))
 }
 
-fn parse_timestamp(raw: &str) -> Result<Timestamp> {
-    let matches = TIMESTAMP_PATTERN
-        .captures(raw)
-        .context(format!("Invalid timestamp '{}'!", raw))?;
-
-    if matches.len() < 4 {
-        bail!(format!(
-            "Invalid timestamp '{}' (Only found {}/4 matches)!",
-            raw,
-            matches.len()
-        ));
+/// Gets the name of the file a track is written to, e.g. '03. Badlands.flac'.
+fn file_name(track: &Track, cutter: Option<&Cutter>) -> Result<String> {
+    let src_ext = track
+        .src
+        .extension()
+        .context("No extension on source file!")?
+        .to_string_lossy()
+        .to_lowercase();
+    let ext = match cutter {
+        Some(cutter) => cutter.extension.unwrap_or(src_ext.as_str()),
+        None if needs_reencode(track)? => "flac",
+        None => src_ext.as_str(),
+    };
+    Ok(format!("{:02}. {}.{}", track.number, track.title, ext))
+}
+
+/// Reads the tracks from a cue sheet or a timestamps file, depending on its extension.
+fn read_tracks(timestamps_file: &Path, src_file: &Path) -> Result<(Album, Vec<Track>)> {
+    let is_cue = timestamps_file
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"));
+    if is_cue {
+        cue::read(timestamps_file, src_file)
+    } else {
+        Ok((
+            Album::default(),
+            timestamps::read(timestamps_file, src_file)?,
+        ))
     }
+}
 
-    Ok(Timestamp {
-        // Can't panic since we check length above
-        disc: matches.get(1).unwrap().as_str().parse()?,
-        track: matches.get(2).unwrap().as_str().parse()?,
-        title: matches.get(3).unwrap().as_str(),
-        start_time: matches.get(4).unwrap().as_str(),
-    })
+/// Reads the tracks from the chapters of `src_file`, along with the album from its tags.
+fn read_chapters(src_file: &Path) -> Result<(Album, Vec<Track>)> {
+    let info = probe::probe(src_file)?;
+    if info.chapters.is_empty() {
+        bail!("{} has no chapters!", src_file.display());
+    }
+    let tags = &info.format.tags;
+    let album = Album {
+        artist: tags
+            .get("album_artist")
+            .or(tags.get("artist"))
+            .map(String::from),
+        // Audiobooks often only have a title.
+        title: tags.get("album").or(tags.get("title")).map(String::from),
+        date: tags.get("date").map(String::from),
+        genre: tags.get("genre").map(String::from),
+    };
+    let tracks = info
+        .chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| Track {
+            src: src_file.to_path_buf(),
+            disc: 1,
+            number: i + 1,
+            title: chapter
+                .title()
+                .map(String::from)
+                .unwrap_or_else(|| format!("Chapter {:02}", i + 1)),
+            start: Duration::from_secs_f64(chapter.start_time.max(0.0)),
+            end: Some(Duration::from_secs_f64(chapter.end_time.max(0.0))),
+            ..Track::default()
+        })
+        .collect();
+    Ok((album, tracks))
 }
 
-#[derive(Debug)]
-struct Timestamp<'a> {
-    disc: usize,
-    track: usize,
-    title: &'a str,
-    start_time: &'a str,
+/// Replaces the titles (and artists, composers and genres, if given) of the tracks with the same
+/// disc and number as the overrides. Their times are ignored.
+fn override_titles(tracks: &mut [Track], overrides: Vec<Track>) {
+    for o in overrides {
+        if let Some(track) = tracks
+            .iter_mut()
+            .find(|t| t.disc == o.disc && t.number == o.number)
+        {
+            track.title = o.title;
+            track.artist = o.artist.or(track.artist.take());
+            track.composer = o.composer.or(track.composer.take());
+            track.genre = o.genre.or(track.genre.take());
+        }
+    }
+}
+
+/// Formats a time in seconds for FFmpeg, e.g. '161.506667'.
+fn format_time(time: Duration) -> String {
+    format!("{:.6}", time.as_secs_f64())
 }
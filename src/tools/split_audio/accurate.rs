@@ -0,0 +1,138 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    probe::{self, CodecType},
+    tools::transcode_audio,
+};
+
+/// Lossless codecs FFmpeg can both encode and put back in the source's container.
+const KEPT_LOSSLESS: &[&str] = &["flac", "alac", "wavpack"];
+
+/// The encoders used to encode lossy sources again, by codec.
+const LOSSY_ENCODERS: &[(&str, &str)] = &[
+    ("mp3", "libmp3lame"),
+    ("aac", "aac"),
+    ("opus", "libopus"),
+    ("vorbis", "libvorbis"),
+];
+
+/// How far before a track's start decoding begins, in seconds. Lossy decoders need some audio
+/// before the start to warm up, or the track would start with a glitch.
+const PREROLL_SECS: u64 = 1;
+
+/// How tracks are cut from a source so they start and end on the exact sample. Cutting with
+/// '-c copy' can only cut between packets, which leaves gaps or overlaps between tracks.
+pub(super) struct Cutter {
+    sample_rate: u32,
+    /// The index of the cover art stream, if there is one. Other video can't be cut this way.
+    cover: Option<usize>,
+    /// The encoder and its options.
+    codec_args: Vec<String>,
+    /// The extension to use instead of the source's, if its container can't hold the codec.
+    pub extension: Option<&'static str>,
+}
+
+impl Cutter {
+    /// Works out how to cut `src`. Lossless sources are encoded losslessly again, so each track
+    /// is exactly the samples between its start and end. Lossy sources have to be encoded again
+    /// too, with the same codec and bitrate. MP3 tracks get a LAME header with the encoder's delay
+    /// and padding, and MP4 tracks an edit list, so players can play them back without gaps.
+    pub fn new(src: &Path) -> Result<Self> {
+        let info = probe::probe(src)?;
+        let stream = info
+            .streams
+            .iter()
+            .find(|s| s.codec_type == CodecType::Audio)
+            .with_context(|| format!("{} has no audio stream!", src.display()))?;
+        let codec = stream.codec_name.as_deref().unwrap_or_default();
+        let sample_rate = stream.sample_rate.with_context(|| {
+            format!("ffprobe didn't report the sample rate of {}", src.display())
+        })?;
+
+        let mut extension = None;
+        let mut codec_args = Vec::new();
+        if codec.starts_with("pcm_") || KEPT_LOSSLESS.contains(&codec) {
+            codec_args.extend(["-c:a".to_string(), codec.to_string()]);
+        } else if let Some((_, encoder)) = LOSSY_ENCODERS.iter().find(|(c, _)| *c == codec) {
+            let bitrate = stream.bit_rate.or(info.format.bit_rate).with_context(|| {
+                format!("ffprobe didn't report the bitrate of {}", src.display())
+            })?;
+            codec_args.extend([
+                "-c:a".to_string(),
+                encoder.to_string(),
+                "-b:a".to_string(),
+                format!("{}k", bitrate.div_ceil(1000)),
+            ]);
+        } else if transcode_audio::is_lossless(codec) {
+            codec_args.extend(["-c:a".to_string(), "flac".to_string()]);
+            extension = Some("flac");
+        } else {
+            bail!("Can't split {} audio accurately!", codec);
+        }
+        // Filtering loses the bit depth, so 24-bit audio would be stored as 32-bit.
+        if let Some(bits) = stream.bits_per_raw_sample.filter(|b| *b > 0) {
+            codec_args.extend(["-bits_per_raw_sample".to_string(), bits.to_string()]);
+        }
+
+        Ok(Self {
+            sample_rate,
+            cover: info
+                .streams
+                .iter()
+                .find(|s| s.disposition.attached_pic)
+                .map(|s| s.index),
+            codec_args,
+            extension,
+        })
+    }
+
+    /// Gets the input options that seek to just before `start`, so the source isn't decoded from
+    /// the beginning for every track. They go before the source's '-i'.
+    pub fn input_args(&self, start: Duration) -> Vec<String> {
+        match seek_point(start).as_secs() {
+            0 => Vec::new(),
+            secs => vec!["-ss".to_string(), secs.to_string()],
+        }
+    }
+
+    /// Gets the arguments that cut from `start` to `end` (or the end of the source) and encode the
+    /// track. Times are rounded to the nearest sample, so a track ends on the sample the next one
+    /// starts on. The source has to be seeked with [`Self::input_args`].
+    pub fn args(&self, start: Duration, end: Option<Duration>) -> Vec<String> {
+        // After seeking, timestamps count from the seek point. It's a whole second, so it's on a
+        // sample and the offsets stay exact.
+        let seek = self.sample(seek_point(start));
+        let mut filter = format!("atrim=start_sample={}", self.sample(start) - seek);
+        if let Some(end) = end {
+            filter.push_str(&format!(
+                ":end_sample={}",
+                self.sample(end).saturating_sub(seek)
+            ));
+        }
+        filter.push_str(",asetpts=PTS-STARTPTS");
+
+        let mut args = vec!["-map".to_string(), "0:a:0".to_string()];
+        if let Some(index) = self.cover {
+            args.extend([
+                "-map".to_string(),
+                format!("0:{}", index),
+                "-c:v".to_string(),
+                "copy".to_string(),
+            ]);
+        }
+        args.extend(["-filter:a".to_string(), filter]);
+        args.extend(self.codec_args.iter().cloned());
+        args
+    }
+
+    fn sample(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * f64::from(self.sample_rate)).round() as u64
+    }
+}
+
+/// Gets the whole second a little before `start` to seek to.
+fn seek_point(start: Duration) -> Duration {
+    Duration::from_secs(start.as_secs().saturating_sub(PREROLL_SECS))
+}
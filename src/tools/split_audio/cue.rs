@@ -0,0 +1,336 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{Album, Track};
+
+/// The number of frames in a second of CD audio, which cue sheet times count in.
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// A track as it's written in the cue sheet, before its end is known.
+struct CueTrack {
+    /// The index of the FILE the track is in, or `None` if it isn't audio, e.g. a data track on
+    /// an enhanced CD.
+    file: Option<usize>,
+    number: usize,
+    title: Option<String>,
+    performer: Option<String>,
+    /// INDEX 01, where the track starts.
+    start: Option<Duration>,
+}
+
+/// Reads the album and its tracks from a cue sheet. If the cue sheet names a single file, the
+/// tracks are cut from `src_file`, since rips are often renamed after the cue sheet is made.
+/// Otherwise each FILE is found relative to the cue sheet.
+pub(super) fn read(cue_file: &Path, src_file: &Path) -> Result<(Album, Vec<Track>)> {
+    let bytes =
+        fs::read(cue_file).with_context(|| format!("Failed to read {}", cue_file.display()))?;
+    let raw = decode(&bytes);
+
+    let mut album = Album::default();
+    let mut files = Vec::<PathBuf>::new();
+    let mut tracks = Vec::<CueTrack>::new();
+    for (i, line) in raw.lines().enumerate() {
+        parse_line(line, &mut album, &mut files, &mut tracks)
+            .with_context(|| format!("{}, line {}", cue_file.display(), i + 1))?;
+    }
+    tracks.retain(|t| t.file.is_some());
+    if tracks.is_empty() {
+        bail!("{} has no audio tracks!", cue_file.display());
+    }
+
+    let cue_dir = cue_file.parent().unwrap_or(Path::new(""));
+    let sources = if files.len() == 1 {
+        vec![src_file.to_path_buf()]
+    } else {
+        files.iter().map(|f| cue_dir.join(f)).collect()
+    };
+
+    let mut result = Vec::new();
+    for (i, track) in tracks.iter().enumerate() {
+        let start = track.start.with_context(|| {
+            format!(
+                "Track {} in {} has no INDEX 01!",
+                track.number,
+                cue_file.display()
+            )
+        })?;
+        // A track ends where the next one in the same file starts.
+        let end = tracks
+            .get(i + 1)
+            .filter(|next| next.file == track.file)
+            .and_then(|next| next.start);
+        result.push(Track {
+            src: sources[track.file.unwrap_or_default()].clone(),
+            disc: 1,
+            number: track.number,
+            title: track
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Track {:02}", track.number)),
+            artist: track
+                .performer
+                .clone()
+                .filter(|p| Some(p) != album.artist.as_ref()),
+            start,
+            end,
+            ..Track::default()
+        });
+    }
+    Ok((album, result))
+}
+
+fn parse_line(
+    line: &str,
+    album: &mut Album,
+    files: &mut Vec<PathBuf>,
+    tracks: &mut Vec<CueTrack>,
+) -> Result<()> {
+    let words = split_words(line.trim());
+    let Some((command, args)) = words.split_first() else {
+        return Ok(());
+    };
+    let arg = |i: usize| {
+        args.get(i)
+            .map(String::as_str)
+            .with_context(|| format!("{} is missing an argument", command))
+    };
+    match command.to_uppercase().as_str() {
+        "FILE" => files.push(PathBuf::from(arg(0)?)),
+        "TRACK" => {
+            let number = arg(0)?
+                .parse()
+                .with_context(|| format!("Invalid track number '{}'", args[0]))?;
+            if files.is_empty() {
+                bail!("TRACK comes before any FILE");
+            }
+            tracks.push(CueTrack {
+                file: arg(1)?
+                    .eq_ignore_ascii_case("AUDIO")
+                    .then_some(files.len() - 1),
+                number,
+                title: None,
+                performer: None,
+                start: None,
+            });
+        }
+        "INDEX" => {
+            let Some(track) = tracks.last_mut() else {
+                bail!("INDEX comes before any TRACK");
+            };
+            // INDEX 00 is the pregap, which belongs to the end of the previous track.
+            if arg(0)?.parse::<u32>().ok() == Some(1) {
+                track.start = Some(parse_time(arg(1)?)?);
+                // With one file per track, a pregap can be at the end of the previous file, with
+                // a FILE between the TRACK and where it really starts.
+                if track.file.is_some() {
+                    track.file = Some(files.len() - 1);
+                }
+            }
+        }
+        "TITLE" => match tracks.last_mut() {
+            Some(track) => track.title = Some(arg(0)?.to_string()),
+            None => album.title = Some(arg(0)?.to_string()),
+        },
+        "PERFORMER" => match tracks.last_mut() {
+            Some(track) => track.performer = Some(arg(0)?.to_string()),
+            None => album.artist = Some(arg(0)?.to_string()),
+        },
+        "REM" => match arg(0).map(str::to_uppercase).as_deref() {
+            Ok("DATE") => album.date = Some(arg(1)?.to_string()),
+            Ok("GENRE") => album.genre = Some(arg(1)?.to_string()),
+            _ => {}
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Splits a line into words, keeping quoted strings together.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let word = chars.by_ref().take_while(|c| *c != '"').collect();
+            words.push(word);
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Parses a cue sheet time like '04:31:62', which is minutes, seconds and frames.
+fn parse_time(time: &str) -> Result<Duration> {
+    let parts = time
+        .split(':')
+        .map(|p| p.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>();
+    let Some(&[minutes, seconds, frames]) = parts.as_deref() else {
+        bail!("Invalid time '{}', expected mm:ss:ff", time);
+    };
+    if seconds >= 60 || frames >= FRAMES_PER_SECOND {
+        bail!("Invalid time '{}', expected mm:ss:ff", time);
+    }
+    let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+    Ok(Duration::from_nanos(
+        frames * 1_000_000_000 / FRAMES_PER_SECOND,
+    ))
+}
+
+/// Decodes a cue sheet. Most are UTF-8, but older rippers write them in the system's code page,
+/// which is read as Latin-1 so accented names mostly survive.
+fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => bytes.iter().map(|&b| char::from(b)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    fn read_str(name: &str, contents: &str) -> Result<(Album, Vec<Track>)> {
+        let path = std::env::temp_dir().join(format!(
+            "mediatools-cue-{}-{}.cue",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents)?;
+        let result = read(&path, Path::new("rip.flac"));
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("00:00:00").unwrap(), secs(0.0));
+        assert_eq!(parse_time("04:05:00").unwrap(), secs(245.0));
+        // The last part is frames, of which there are 75 a second.
+        assert_eq!(parse_time("00:01:15").unwrap(), secs(1.2));
+        assert_eq!(
+            parse_time("00:00:74").unwrap(),
+            Duration::from_nanos(986_666_666)
+        );
+        // Minutes can go past an hour, since there's no hours part.
+        assert_eq!(parse_time("75:00:00").unwrap(), secs(4500.0));
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        for time in [
+            "",
+            "04:05",
+            "1:04:05:00",
+            "04:60:00",
+            "04:05:75",
+            "04:05:-1",
+            "a:05:00",
+        ] {
+            assert!(parse_time(time).is_err(), "'{}' should be rejected", time);
+        }
+    }
+
+    #[test]
+    fn splits_words() {
+        assert_eq!(split_words("TRACK 01 AUDIO"), ["TRACK", "01", "AUDIO"]);
+        assert_eq!(
+            split_words("TITLE \"Break Out - Version 1\""),
+            ["TITLE", "Break Out - Version 1"]
+        );
+        assert_eq!(
+            split_words("FILE \"My Rip.flac\"   WAVE"),
+            ["FILE", "My Rip.flac", "WAVE"]
+        );
+        assert_eq!(split_words("TITLE \"\""), ["TITLE", ""]);
+        // An unclosed quote runs to the end of the line.
+        assert_eq!(split_words("TITLE \"Unfinished"), ["TITLE", "Unfinished"]);
+        assert!(split_words("   ").is_empty());
+    }
+
+    #[test]
+    fn decodes_latin1() {
+        assert_eq!(
+            decode(b"\xEF\xBB\xBFTITLE \"Caf\xC3\xA9\""),
+            "TITLE \"Café\""
+        );
+        assert_eq!(decode(b"TITLE \"Caf\xE9\""), "TITLE \"Café\"");
+    }
+
+    #[test]
+    fn reads_files() {
+        let (album, tracks) = read_str(
+            "single",
+            "REM DATE 1998\n\
+             PERFORMER \"Some Band\"\n\
+             TITLE \"An Album\"\n\
+             FILE \"original name.wav\" WAVE\n  \
+               TRACK 01 AUDIO\n    \
+                 TITLE \"First Song\"\n    \
+                 PERFORMER \"Some Band\"\n    \
+                 INDEX 01 00:00:00\n  \
+               TRACK 02 AUDIO\n    \
+                 PERFORMER \"Someone Else\"\n    \
+                 INDEX 00 04:03:00\n    \
+                 INDEX 01 04:05:15\n",
+        )
+        .unwrap();
+        assert_eq!(album.title.as_deref(), Some("An Album"));
+        assert_eq!(album.artist.as_deref(), Some("Some Band"));
+        assert_eq!(album.date.as_deref(), Some("1998"));
+
+        assert_eq!(tracks.len(), 2);
+        // A single FILE is taken from the source file, not the cue sheet.
+        assert_eq!(tracks[0].src, Path::new("rip.flac"));
+        assert_eq!(tracks[0].title, "First Song");
+        assert_eq!(tracks[0].artist, None);
+        assert_eq!(tracks[0].end, Some(secs(245.2)));
+        assert_eq!(tracks[1].title, "Track 02");
+        assert_eq!(tracks[1].artist.as_deref(), Some("Someone Else"));
+        assert_eq!(tracks[1].start, secs(245.2));
+        assert_eq!(tracks[1].end, None);
+    }
+
+    #[test]
+    fn skips_data_tracks() {
+        let (_, tracks) = read_str(
+            "data",
+            "FILE \"a.bin\" BINARY\nTRACK 01 MODE1/2352\nINDEX 01 00:00:00\n\
+             FILE \"b.flac\" WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n",
+        )
+        .unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].number, 2);
+        assert!(tracks[0].src.ends_with("b.flac"));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(read_str("empty", "TITLE \"Nothing\"\n").is_err());
+        assert!(read_str("no-file", "TRACK 01 AUDIO\n").is_err());
+        assert!(read_str("no-index", "FILE a.wav WAVE\nTRACK 01 AUDIO\n").is_err());
+        assert!(read_str(
+            "bad-time",
+            "FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 1:2\n"
+        )
+        .is_err());
+    }
+}
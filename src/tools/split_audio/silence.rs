@@ -0,0 +1,151 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use lazy_regex::{lazy_regex, Lazy, Regex};
+use simplelog::{info, warn};
+
+use super::timestamps::{self, VERSION};
+use crate::{path_to_str, plan, probe, utils};
+
+/// How close to the end of the file a silence can end and still count as running to the end, in
+/// seconds.
+const END_MARGIN: f64 = 0.5;
+
+static SILENCE_START_PATTERN: Lazy<Regex> = lazy_regex!(r"silence_start: (-?[\d.]+)");
+static SILENCE_END_PATTERN: Lazy<Regex> = lazy_regex!(r"silence_end: (-?[\d.]+)");
+
+/// How silence is detected.
+pub struct Detection {
+    /// The level below which audio counts as silence, e.g. '-50dB' or '0.001'.
+    pub noise: String,
+    /// How long a silence must be to split at, in seconds.
+    pub min_duration: f64,
+    /// The number of tracks expected. If set, only the longest silences are split at.
+    pub tracks: Option<usize>,
+}
+
+/// A silence between tracks, in seconds.
+struct Silence {
+    start: f64,
+    end: f64,
+}
+
+impl Silence {
+    fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// Finds the silences in `src_file` and writes a track starting in the middle of each to
+/// `timestamps_file`, in the timestamps format, so they can be reviewed before splitting.
+pub fn propose(
+    src_file: &Path,
+    timestamps_file: &Path,
+    detection: &Detection,
+    overwrite: bool,
+) -> Result<()> {
+    if timestamps_file.exists() && !overwrite {
+        bail!(
+            "{} already exists! Use '--overwrite' to replace it.",
+            timestamps_file.display()
+        );
+    }
+    if detection.tracks == Some(0) {
+        bail!("The expected number of tracks must be at least 1!");
+    }
+
+    info!("Detecting silence in {}...", src_file.display());
+    let src_duration = probe::duration(src_file)?.as_secs_f64();
+    let filter = format!(
+        "silencedetect=noise={}:d={}",
+        detection.noise, detection.min_duration
+    );
+    let output = utils::ffmpeg_output([
+        "-i",
+        path_to_str!(src_file)?,
+        "-map",
+        "0:a:0",
+        "-filter:a",
+        &filter,
+        "-f",
+        "null",
+        "-",
+    ])
+    .with_context(|| format!("Failed to detect silence in {}", src_file.display()))?;
+
+    let mut silences = parse_silences(&output, src_duration);
+    if let Some(tracks) = detection.tracks {
+        let needed = tracks - 1;
+        if silences.len() < needed {
+            warn!(
+                "Only found {} silence(s) for {} tracks. Try a higher noise level or a shorter \
+                 minimum duration.",
+                silences.len(),
+                tracks
+            );
+        } else {
+            // Keep the longest silences, which are the likeliest to be between tracks.
+            silences.sort_by(|a, b| b.duration().total_cmp(&a.duration()));
+            silences.truncate(needed);
+            silences.sort_by(|a, b| a.start.total_cmp(&b.start));
+        }
+    }
+
+    let starts = std::iter::once(0.0)
+        .chain(silences.iter().map(|s| (s.start + s.end) / 2.0))
+        .collect::<Vec<_>>();
+    let lines = starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| {
+            format!(
+                "{} | 1 | {} | Track {:02}",
+                timestamps::format_time(Duration::from_secs_f64(*start)),
+                i + 1,
+                i + 1
+            )
+        })
+        .collect::<Vec<_>>();
+    for line in &lines {
+        info!("{}", line);
+    }
+    let contents = format!(
+        "# mediatools timestamps v{}\n{}\n",
+        VERSION,
+        lines.join("\n")
+    );
+
+    if plan::is_dry_run() {
+        info!(
+            "Would write {} track(s) to {}",
+            lines.len(),
+            timestamps_file.display()
+        );
+        return Ok(());
+    }
+    fs::write(timestamps_file, contents)
+        .with_context(|| format!("Failed to write {}", timestamps_file.display()))?;
+    info!(
+        "Wrote {} track(s) to {}. Check them, then split with it.",
+        lines.len(),
+        timestamps_file.display()
+    );
+    Ok(())
+}
+
+/// Reads the silences silencedetect found, leaving out any at the very start or end since they
+/// aren't between tracks.
+fn parse_silences(output: &str, src_duration: f64) -> Vec<Silence> {
+    let starts = SILENCE_START_PATTERN
+        .captures_iter(output)
+        .filter_map(|c| c[1].parse::<f64>().ok());
+    let ends = SILENCE_END_PATTERN
+        .captures_iter(output)
+        .filter_map(|c| c[1].parse::<f64>().ok());
+    // A silence that runs to the end of the file has no end.
+    starts
+        .zip(ends.map(Some).chain(std::iter::repeat(None)))
+        .filter_map(|(start, end)| Some(Silence { start, end: end? }))
+        .filter(|s| s.start > 0.0 && s.end + END_MARGIN < src_duration)
+        .collect()
+}
@@ -0,0 +1,572 @@
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use lazy_regex::{lazy_regex, Lazy, Regex};
+
+use super::Track;
+
+/// The newest version of the timestamps format.
+pub(super) const VERSION: u32 = 2;
+
+/// The line that marks which version of the format a file is in.
+static VERSION_PATTERN: Lazy<Regex> = lazy_regex!(r"(?i)^#\s*mediatools timestamps v(\d+)\s*$");
+
+// Version 1: CD | Track | Title | Start Time
+// Examples:
+// 1 1 Break Out - Version 1 0:00
+// 4 1 Prove It All Night - Version 1 2:41:38
+// The start is matched loosely so a time with milliseconds is reported instead of being cut short.
+static TIMESTAMP_PATTERN: Lazy<Regex> =
+    lazy_regex!(r"^\s*([0-9]+) ([0-9]+) (.*) ([0-9]+:[0-9:.]+)\s*$");
+
+/// The overrides a track can have in version 2, besides its title.
+const KEYS: &[&str] = &["artist", "composer", "genre"];
+
+/// A problem in a timestamps file, and where it is.
+#[derive(Debug)]
+struct ParseError {
+    column: usize,
+    message: String,
+}
+
+fn error<T>(column: usize, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError {
+        column,
+        message: message.into(),
+    })
+}
+
+/// Reads a timestamps file. Lines starting with '#' are comments, and the first line can say
+/// which version of the format the file is in, e.g. '# mediatools timestamps v2'. Files without
+/// one are version 1.
+///
+/// Version 1 has a track on each line as 'DISC TRACK TITLE START', e.g. '1 3 Badlands 4:05'. The
+/// start is 'M:SS' or 'H:MM:SS'.
+///
+/// Version 2 has a track on each line as fields separated by '|':
+///
+/// ```text
+/// START[-END] | [DISC] | [TRACK] | TITLE [| KEY=VALUE]...
+/// START[-END] | TITLE [| KEY=VALUE]...
+/// ```
+///
+/// - Times are '[[H:]MM:]SS[.mmm]', e.g. '4:05', '1:02:03.250' or '95.5'.
+/// - Without an end, a track ends where the next one starts.
+/// - An empty disc is the previous track's disc (or 1), and an empty track is the number after the
+///   previous track on the same disc (or 1).
+/// - The title is taken as it is, so it can contain ' - ' but not '|'.
+/// - KEY is 'artist', 'composer' or 'genre' and overrides it for that track.
+///
+/// ```text
+/// # mediatools timestamps v2
+/// 0:00            | 1 | 1 | Badlands
+/// 4:03.493        |   |   | Adam Raised a Cain | artist=Guest
+/// 1:02:03.250-1:05:00 | 2 | | Encore | genre=Live
+/// ```
+///
+/// The tracks are cut from `src_file`.
+pub(super) fn read(timestamps_file: &Path, src_file: &Path) -> Result<Vec<Track>> {
+    let raw = fs::read_to_string(timestamps_file)
+        .with_context(|| format!("Failed to read {}", timestamps_file.display()))?;
+    let mut lines = raw
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let mut version = 1;
+    let mut first = lines.next();
+    if let Some((number, line)) = first {
+        if let Some(captures) = VERSION_PATTERN.captures(line.trim()) {
+            version = captures[1].parse().unwrap_or(0);
+            if !(1..=VERSION).contains(&version) {
+                bail!(
+                    "{}, line {}: Unknown timestamps version '{}'! The newest is {}.",
+                    timestamps_file.display(),
+                    number,
+                    &captures[1],
+                    VERSION
+                );
+            }
+            first = None;
+        }
+    }
+
+    let mut tracks = Vec::<Track>::new();
+    let mut problems = Vec::new();
+    for (number, line) in first.into_iter().chain(lines) {
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let parsed = match version {
+            1 => parse_v1(line),
+            _ => parse_v2(line, tracks.last()),
+        };
+        // Keep going so every problem is reported at once.
+        let (mut track, explicit_end) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                problems.push(format!(
+                    "Line {}, column {}: {}",
+                    number, e.column, e.message
+                ));
+                continue;
+            }
+        };
+        track.src = src_file.to_path_buf();
+        // Tracks without an end run until the next one starts, or to the end of the source.
+        if let Some(previous) = tracks.last_mut().filter(|p| p.end.is_none()) {
+            previous.end = Some(track.start);
+        }
+        track.end = explicit_end;
+        tracks.push(track);
+    }
+    if !problems.is_empty() {
+        bail!(
+            "Found {} problem(s) in {}:\n  {}",
+            problems.len(),
+            timestamps_file.display(),
+            problems.join("\n  ")
+        );
+    }
+    Ok(tracks)
+}
+
+/// Parses a version 1 line.
+fn parse_v1(line: &str) -> Result<(Track, Option<Duration>), ParseError> {
+    let Some(captures) = TIMESTAMP_PATTERN.captures(line) else {
+        return error(
+            1,
+            "Expected 'DISC TRACK TITLE START', e.g. '1 3 Badlands 4:05'",
+        );
+    };
+    // Can't panic since every group has to match.
+    let field = |i: usize| captures.get(i).unwrap();
+    let number = |i: usize| {
+        field(i).as_str().parse::<usize>().or_else(|_| {
+            error(
+                line[..field(i).start()].chars().count() + 1,
+                format!("Invalid number '{}'", field(i).as_str()),
+            )
+        })
+    };
+    let start = field(4);
+    let start_column = line[..start.start()].chars().count() + 1;
+    if start.as_str().contains('.') {
+        return error(
+            start_column,
+            format!(
+                "Version 1 times can't have milliseconds, found '{}'. Add '# mediatools \
+                 timestamps v{}' as the first line and use version 2 to have them",
+                start.as_str(),
+                VERSION
+            ),
+        );
+    }
+    let start_time = parse_time(start.as_str(), start_column)?;
+    Ok((
+        Track {
+            disc: number(1)?,
+            number: number(2)?,
+            title: field(3).as_str().to_string(),
+            start: start_time,
+            ..Track::default()
+        },
+        None,
+    ))
+}
+
+/// Parses a version 2 line, numbering the track after `previous` if its numbers are left out.
+fn parse_v2(line: &str, previous: Option<&Track>) -> Result<(Track, Option<Duration>), ParseError> {
+    // Each field with the column it starts at.
+    let mut fields = Vec::new();
+    let mut column = 1;
+    for field in line.split('|') {
+        let leading = field.chars().take_while(|c| c.is_whitespace()).count();
+        fields.push((column + leading, field.trim()));
+        column += field.chars().count() + 1;
+    }
+
+    let (time_column, time) = fields[0];
+    let (start, end) = match time.split_once('-') {
+        Some((start, end)) => {
+            let end_column = time_column + start.chars().count() + 1;
+            let end_column = end_column + end.chars().take_while(|c| c.is_whitespace()).count();
+            (
+                parse_time(start.trim(), time_column)?,
+                Some(parse_time(end.trim(), end_column)?),
+            )
+        }
+        None => (parse_time(time, time_column)?, None),
+    };
+    if let Some(end) = end {
+        if end <= start {
+            return error(time_column, "The end must be after the start");
+        }
+    }
+
+    let mut track = Track {
+        start,
+        ..Track::default()
+    };
+    let overrides_from = fields
+        .iter()
+        .skip(1)
+        .position(|(_, f)| is_override(f))
+        .map_or(fields.len(), |i| i + 1);
+    let (disc, number, (title_column, title)) = match &fields[1..overrides_from] {
+        [title] => (None, None, *title),
+        [disc, number, title] => (Some(*disc), Some(*number), *title),
+        positional => {
+            // Most likely a misspelled key.
+            if let Some((column, key)) = positional.iter().find_map(|(column, f)| {
+                let (key, _) = f.split_once('=')?;
+                key.trim()
+                    .chars()
+                    .all(|c| c.is_ascii_alphabetic())
+                    .then_some((*column, key.trim()))
+            }) {
+                return error(
+                    column,
+                    format!("Unknown key '{}', expected one of {}", key, KEYS.join(", ")),
+                );
+            }
+            return error(
+                fields.get(1).map_or(column, |f| f.0),
+                "Expected 'START | TITLE' or 'START | DISC | TRACK | TITLE'",
+            );
+        }
+    };
+    if title.is_empty() {
+        return error(title_column, "The title is empty");
+    }
+    track.title = title.to_string();
+
+    let parse_number = |field: Option<(usize, &str)>| match field {
+        Some((_, "")) | None => Ok(None),
+        Some((column, raw)) => match raw.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => error(column, format!("Invalid number '{}'", raw)),
+        },
+    };
+    track.disc = parse_number(disc)?.unwrap_or(previous.map_or(1, |p| p.disc));
+    track.number = match parse_number(number)? {
+        Some(n) => n,
+        None => match previous {
+            Some(p) if p.disc == track.disc => p.number + 1,
+            _ => 1,
+        },
+    };
+
+    for &(column, field) in &fields[overrides_from..] {
+        let Some((key, value)) = field.split_once('=').filter(|_| is_override(field)) else {
+            return error(
+                column,
+                format!(
+                    "Expected KEY=VALUE, where KEY is one of {}",
+                    KEYS.join(", ")
+                ),
+            );
+        };
+        let value = Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        match key.trim().to_lowercase().as_str() {
+            "artist" => track.artist = value,
+            "composer" => track.composer = value,
+            "genre" => track.genre = value,
+            _ => unreachable!("is_override only accepts known keys"),
+        }
+    }
+    Ok((track, end))
+}
+
+/// Whether a field is an override like 'artist=Bruce Springsteen' rather than a title.
+fn is_override(field: &str) -> bool {
+    field
+        .split_once('=')
+        .is_some_and(|(key, _)| KEYS.contains(&key.trim().to_lowercase().as_str()))
+}
+
+/// Parses a version 2 time like '4:05', '1:02:03.250' or '95.5'. `column` is where it starts.
+fn parse_time(raw: &str, column: usize) -> Result<Duration, ParseError> {
+    if raw.is_empty() {
+        return error(column, "Expected a time like '4:05' or '1:02:03.250'");
+    }
+    let (whole, fraction) = match raw.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (raw, None),
+    };
+    let parts = whole.split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return error(
+            column,
+            format!("Invalid time '{}', expected [[H:]MM:]SS[.mmm]", raw),
+        );
+    }
+
+    let mut secs = 0u64;
+    let mut offset = column;
+    for (i, part) in parts.iter().enumerate() {
+        let Ok(value) = part.parse::<u64>() else {
+            return error(
+                offset,
+                format!("Invalid number '{}' in time '{}'", part, raw),
+            );
+        };
+        // Everything but the first part is minutes or seconds, which need two digits.
+        if i > 0 && (part.len() != 2 || value >= 60) {
+            return error(
+                offset,
+                format!(
+                    "Expected two digits from 00 to 59, found '{}' in time '{}'",
+                    part, raw
+                ),
+            );
+        }
+        secs = secs * 60 + value;
+        offset += part.len() + 1;
+    }
+
+    let mut millis = 0;
+    if let Some(fraction) = fraction {
+        if fraction.is_empty()
+            || fraction.len() > 3
+            || !fraction.bytes().all(|b| b.is_ascii_digit())
+        {
+            return error(
+                offset,
+                format!(
+                    "Expected 1 to 3 digits of milliseconds, found '{}'",
+                    fraction
+                ),
+            );
+        }
+        millis = format!("{:0<3}", fraction)
+            .parse::<u64>()
+            .unwrap_or_default();
+    }
+    Ok(Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
+/// Formats a time as version 2 does, e.g. '4:05.250' or '1:02:03.000'.
+pub(super) fn format_time(time: Duration) -> String {
+    let millis = time.as_millis();
+    let (secs, millis) = (millis / 1000, millis % 1000);
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}.{:03}", hours, minutes, secs, millis)
+    } else {
+        format!("{}:{:02}.{:03}", minutes, secs, millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    /// Gets the column and message of an error, panicking if there wasn't one.
+    fn problem<T: std::fmt::Debug>(result: Result<T, ParseError>) -> (usize, String) {
+        let e = result.expect_err("expected a parse error");
+        (e.column, e.message)
+    }
+
+    fn previous(disc: usize, number: usize) -> Track {
+        Track {
+            disc,
+            number,
+            ..Track::default()
+        }
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("0", 1).unwrap(), secs(0.0));
+        assert_eq!(parse_time("95.5", 1).unwrap(), secs(95.5));
+        assert_eq!(parse_time("4:05", 1).unwrap(), secs(245.0));
+        assert_eq!(parse_time("4:05.05", 1).unwrap(), secs(245.05));
+        assert_eq!(parse_time("1:02:03.250", 1).unwrap(), secs(3723.25));
+        assert_eq!(parse_time("100:00", 1).unwrap(), secs(6000.0));
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        assert_eq!(problem(parse_time("", 5)).0, 5);
+        assert_eq!(problem(parse_time("a:05", 5)).0, 5);
+        // The column points at the part that's wrong.
+        assert_eq!(problem(parse_time("4:5", 5)).0, 7);
+        assert_eq!(problem(parse_time("4:60", 5)).0, 7);
+        assert_eq!(problem(parse_time("1:02:3", 5)).0, 10);
+        assert_eq!(problem(parse_time("4:05.1234", 5)).0, 10);
+        assert_eq!(problem(parse_time("4:05.", 5)).0, 10);
+        assert!(problem(parse_time("1:02:03:04", 5))
+            .1
+            .contains("[[H:]MM:]SS"));
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_time(secs(0.0)), "0:00.000");
+        assert_eq!(format_time(secs(245.25)), "4:05.250");
+        assert_eq!(format_time(secs(3723.0)), "1:02:03.000");
+        for time in ["4:05.250", "1:02:03.000"] {
+            assert_eq!(format_time(parse_time(time, 1).unwrap()), time);
+        }
+    }
+
+    #[test]
+    fn parses_v1_lines() {
+        let (track, end) = parse_v1("1 3 Break Out - Version 1 4:05").unwrap();
+        assert_eq!((track.disc, track.number), (1, 3));
+        assert_eq!(track.title, "Break Out - Version 1");
+        assert_eq!(track.start, secs(245.0));
+        assert_eq!(end, None);
+
+        let (track, _) = parse_v1("4 1 Prove It All Night 2:41:38").unwrap();
+        assert_eq!(track.start, secs(9698.0));
+    }
+
+    #[test]
+    fn rejects_bad_v1_lines() {
+        // Milliseconds need version 2 rather than being dropped.
+        let (column, message) = problem(parse_v1("1 1 Badlands 4:05.500"));
+        assert_eq!(column, 14);
+        assert!(message.contains("v2"));
+        assert_eq!(problem(parse_v1("1 1 X 1:02:03.25")).0, 7);
+        assert_eq!(problem(parse_v1("1 1 X 1:2:3")).0, 9);
+        assert_eq!(problem(parse_v1("1 1 Take 5")).0, 1);
+    }
+
+    #[test]
+    fn parses_v2_lines() {
+        let (track, end) = parse_v2("4:03.493 | 1 | 2 | Adam Raised a Cain", None).unwrap();
+        assert_eq!((track.disc, track.number), (1, 2));
+        assert_eq!(track.title, "Adam Raised a Cain");
+        assert_eq!(track.start, secs(243.493));
+        assert_eq!(end, None);
+
+        let (track, end) = parse_v2("1:02:03.250-1:05:00 | Encore - Live", None).unwrap();
+        assert_eq!(track.title, "Encore - Live");
+        assert_eq!(track.start, secs(3723.25));
+        assert_eq!(end, Some(secs(3900.0)));
+    }
+
+    #[test]
+    fn numbers_omitted_tracks() {
+        let (track, _) = parse_v2("0:00 | First", None).unwrap();
+        assert_eq!((track.disc, track.number), (1, 1));
+        let (track, _) = parse_v2("4:00 | | | Next", Some(&previous(1, 3))).unwrap();
+        assert_eq!((track.disc, track.number), (1, 4));
+        let (track, _) = parse_v2("4:00 | Next", Some(&previous(2, 3))).unwrap();
+        assert_eq!((track.disc, track.number), (2, 4));
+        // A new disc starts counting again.
+        let (track, _) = parse_v2("4:00 | 2 | | New disc", Some(&previous(1, 9))).unwrap();
+        assert_eq!((track.disc, track.number), (2, 1));
+        let (track, _) = parse_v2("4:00 | | 7 | Skipped ahead", Some(&previous(1, 3))).unwrap();
+        assert_eq!((track.disc, track.number), (1, 7));
+    }
+
+    #[test]
+    fn parses_overrides() {
+        let (track, _) = parse_v2(
+            "0:00 | Title | artist=Guest | Composer = Someone Else | genre=",
+            None,
+        )
+        .unwrap();
+        assert_eq!(track.title, "Title");
+        assert_eq!(track.artist.as_deref(), Some("Guest"));
+        assert_eq!(track.composer.as_deref(), Some("Someone Else"));
+        // An empty value keeps the album's.
+        assert_eq!(track.genre, None);
+
+        let (track, _) = parse_v2("0:00 | 1 | 1 | E=mc2 | genre=Rock", None).unwrap();
+        assert_eq!(track.title, "E=mc2");
+        assert_eq!(track.genre.as_deref(), Some("Rock"));
+    }
+
+    #[test]
+    fn rejects_bad_v2_lines() {
+        let (column, message) = problem(parse_v2("0:00 | 1 | 1 | Title | atrist=X", None));
+        assert_eq!(column, 24);
+        assert!(message.contains("'atrist'"));
+        let (column, message) = problem(parse_v2("0:00 | Title | artist=X | oops", None));
+        assert_eq!(column, 27);
+        assert!(message.contains("KEY=VALUE"));
+
+        assert_eq!(problem(parse_v2("5:00-4:00 | Title", None)).0, 1);
+        assert_eq!(problem(parse_v2("5:00-5:00 | Title", None)).0, 1);
+        assert_eq!(problem(parse_v2("5:00-4:5 | Title", None)).0, 8);
+        assert_eq!(problem(parse_v2("  5:0 | Title", None)).0, 5);
+        assert_eq!(problem(parse_v2("0:00 | x | 1 | Title", None)).0, 8);
+        assert_eq!(problem(parse_v2("0:00 | 1 | 0 | Title", None)).0, 12);
+        assert_eq!(problem(parse_v2("0:00 | 1 | 1 |  ", None)).0, 17);
+        assert_eq!(problem(parse_v2("0:00 | 1 | Title", None)).0, 8);
+        // A missing title is reported where it should be, at the end of the line.
+        assert_eq!(problem(parse_v2("0:00", None)).0, 6);
+    }
+
+    /// Writes `contents` to a timestamps file and reads it.
+    fn read_str(name: &str, contents: &str) -> Result<Vec<Track>> {
+        let path = std::env::temp_dir().join(format!(
+            "mediatools-timestamps-{}-{}.txt",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents)?;
+        let result = read(&path, Path::new("source.flac"));
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn reads_files() {
+        let tracks = read_str(
+            "v2",
+            "# mediatools timestamps v2\n\
+             # A comment\n\
+             0:00 | 1 | 1 | Badlands\n\
+             \n\
+             4:03.493 | Adam Raised a Cain\n\
+             7:00-8:00 | Something in the Night\n\
+             9:00 | 2 | | Candy's Room\n",
+        )
+        .unwrap();
+        let summary = tracks
+            .iter()
+            .map(|t| (t.disc, t.number, t.start, t.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (1, 1, secs(0.0), Some(secs(243.493))),
+                (1, 2, secs(243.493), Some(secs(420.0))),
+                (1, 3, secs(420.0), Some(secs(480.0))),
+                (2, 1, secs(540.0), None),
+            ]
+        );
+        assert!(tracks.iter().all(|t| t.src == Path::new("source.flac")));
+
+        // Without a version line, it's version 1.
+        let tracks = read_str("v1", "1 1 Badlands 0:00\n1 2 Adam Raised a Cain 4:03\n").unwrap();
+        assert_eq!(tracks[0].end, Some(secs(243.0)));
+        assert_eq!(tracks[1].end, None);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let e = read_str(
+            "bad",
+            "# mediatools timestamps v2\n0:00 | A\n4:5 | B\n5:00 | C | mood=happy\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(e.starts_with("Found 2 problem(s)"), "{}", e);
+        assert!(e.contains("Line 3, column 3"), "{}", e);
+        assert!(e.contains("Line 4, column 12"), "{}", e);
+
+        let e = read_str("v9", "# mediatools timestamps v9\n").unwrap_err();
+        assert!(e.to_string().contains("Unknown timestamps version '9'"));
+    }
+}
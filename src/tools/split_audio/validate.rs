@@ -0,0 +1,251 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use simplelog::warn;
+
+use super::{timestamps::format_time, Track};
+
+/// How far past the end of the source a time can be, since the duration FFmpeg reports is rounded.
+const DURATION_TOLERANCE: Duration = Duration::from_millis(50);
+
+/// The longest file name most file systems allow, in bytes.
+const MAX_FILE_NAME: usize = 255;
+
+/// Characters that can't be in a file name on this platform.
+const UNSAFE_CHARS: &[char] = if cfg!(windows) {
+    &['/', '\\', '<', '>', ':', '"', '|', '?', '*']
+} else {
+    &['/']
+};
+
+/// Checks the tracks before anything is cut, so mistakes don't leave broken tracks behind. Every
+/// problem is reported at once. `durations` are those of the sources, if they could be probed, and
+/// `file_names` the name each track will be written to.
+pub(super) fn check(
+    tracks: &[Track],
+    durations: &BTreeMap<PathBuf, Option<Duration>>,
+    file_names: &[String],
+) -> Result<()> {
+    for (src, _) in durations.iter().filter(|(_, d)| d.is_none()) {
+        warn!(
+            "Couldn't get the duration of {}, so the times can't be checked against it.",
+            src.display()
+        );
+    }
+
+    let mut problems = Vec::new();
+    let mut numbers = BTreeSet::new();
+    let mut previous_starts = BTreeMap::<&PathBuf, &Track>::new();
+    for (track, file_name) in tracks.iter().zip(file_names) {
+        let name = format!(
+            "Disc {}, track {} '{}'",
+            track.disc, track.number, track.title
+        );
+
+        if !numbers.insert((track.disc, track.number)) {
+            problems.push(format!("{} has the same number as an earlier track", name));
+        }
+
+        if let Some(previous) = previous_starts.insert(&track.src, track) {
+            if track.start <= previous.start {
+                problems.push(format!(
+                    "{} starts at {}, which isn't after the track before it ({})",
+                    name,
+                    format_time(track.start),
+                    format_time(previous.start)
+                ));
+            } else if let Some(end) = previous.end.filter(|end| *end > track.start) {
+                problems.push(format!(
+                    "{} starts at {}, before the track before it ends ({})",
+                    name,
+                    format_time(track.start),
+                    format_time(end)
+                ));
+            }
+        }
+        if let Some(end) = track.end {
+            if end <= track.start {
+                problems.push(format!(
+                    "{} ends at {}, which isn't after it starts ({})",
+                    name,
+                    format_time(end),
+                    format_time(track.start)
+                ));
+            }
+        }
+
+        if let Some(duration) = durations.get(&track.src).copied().flatten() {
+            if track.start >= duration {
+                problems.push(format!(
+                    "{} starts at {}, after the end of {} ({})",
+                    name,
+                    format_time(track.start),
+                    track.src.display(),
+                    format_time(duration)
+                ));
+            } else if let Some(end) = track.end.filter(|end| *end > duration + DURATION_TOLERANCE) {
+                problems.push(format!(
+                    "{} ends at {}, after the end of {} ({})",
+                    name,
+                    format_time(end),
+                    track.src.display(),
+                    format_time(duration)
+                ));
+            }
+        }
+
+        if let Some(problem) = file_name_problem(&track.title, file_name) {
+            problems.push(format!("{}: {}", name, problem));
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Found {} problem(s) with the tracks, so nothing was split:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Checks that a title can be used in a file name.
+fn file_name_problem(title: &str, file_name: &str) -> Option<String> {
+    if title.trim().is_empty() {
+        return Some("The title is empty".to_string());
+    }
+    if let Some(c) = title
+        .chars()
+        .find(|c| UNSAFE_CHARS.contains(c) || c.is_control())
+    {
+        return Some(format!(
+            "The title can't be in a file name because of {:?}",
+            c
+        ));
+    }
+    if file_name.len() > MAX_FILE_NAME {
+        return Some(format!(
+            "The title is too long for a file name ({} of {} bytes)",
+            file_name.len(),
+            MAX_FILE_NAME
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn track(number: usize, title: &str, start: u64, end: Option<u64>) -> Track {
+        Track {
+            src: PathBuf::from("source.flac"),
+            disc: 1,
+            number,
+            title: title.to_string(),
+            start: secs(start),
+            end: end.map(secs),
+            ..Track::default()
+        }
+    }
+
+    /// Checks the tracks against a 10 minute source, returning the problems found.
+    fn problems(tracks: &[Track]) -> Vec<String> {
+        let durations = BTreeMap::from([(PathBuf::from("source.flac"), Some(secs(600)))]);
+        let file_names = tracks
+            .iter()
+            .map(|t| format!("{:02} {}.flac", t.number, t.title))
+            .collect::<Vec<_>>();
+        match check(tracks, &durations, &file_names) {
+            Ok(()) => Vec::new(),
+            Err(e) => e.to_string().lines().skip(1).map(str::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_good_tracks() {
+        let tracks = [
+            track(1, "One", 0, Some(200)),
+            track(2, "Two", 200, None),
+            track(3, "Three: The Sequel?", 400, Some(600)),
+        ];
+        assert_eq!(problems(&tracks), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_overlapping_times() {
+        let found = problems(&[
+            track(1, "One", 0, Some(250)),
+            track(2, "Two", 200, None),
+            track(3, "Three", 200, None),
+            track(4, "Four", 500, Some(450)),
+        ]);
+        assert_eq!(found.len(), 3, "{:?}", found);
+        assert!(found[0].contains("track 2 'Two' starts at 3:20.000, before the track"));
+        assert!(found[1].contains("track 3 'Three' starts at 3:20.000, which isn't after"));
+        assert!(found[2].contains("track 4 'Four' ends at 7:30.000, which isn't after it"));
+    }
+
+    #[test]
+    fn rejects_times_past_the_end() {
+        let found = problems(&[track(1, "One", 0, Some(700)), track(2, "Two", 700, None)]);
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0].contains("ends at 11:40.000, after the end of source.flac"));
+        assert!(found[1].contains("starts at 11:40.000, after the end of source.flac"));
+
+        // FFmpeg's rounded duration is allowed to be a little short.
+        let mut track = track(1, "One", 0, None);
+        track.end = Some(secs(600) + Duration::from_millis(40));
+        assert!(problems(&[track]).is_empty());
+    }
+
+    #[test]
+    fn skips_unknown_durations() {
+        let durations = BTreeMap::from([(PathBuf::from("source.flac"), None)]);
+        let tracks = [track(1, "One", 9000, None)];
+        assert!(check(&tracks, &durations, &["01 One.flac".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_numbers() {
+        let found = problems(&[track(1, "One", 0, None), track(1, "Two", 100, None)]);
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(found[0].contains("same number as an earlier track"));
+
+        // The same number on another disc is fine.
+        let mut other_disc = track(1, "Two", 100, None);
+        other_disc.disc = 2;
+        assert!(problems(&[track(1, "One", 0, None), other_disc]).is_empty());
+    }
+
+    #[test]
+    fn rejects_unsafe_titles() {
+        assert_eq!(
+            file_name_problem("  ", "01   .flac").as_deref(),
+            Some("The title is empty")
+        );
+        assert!(file_name_problem("AC/DC", "01 AC/DC.flac")
+            .unwrap()
+            .contains("'/'"));
+        assert!(file_name_problem("Tab\there", "01 Tab\there.flac")
+            .unwrap()
+            .contains("'\\t'"));
+        let long = "a".repeat(MAX_FILE_NAME);
+        assert!(file_name_problem(&long, &format!("01 {}.flac", long))
+            .unwrap()
+            .contains("too long"));
+        assert_eq!(file_name_problem("Café", "01 Café.flac"), None);
+
+        // Every problem is reported, not just the first.
+        let found = problems(&[track(1, "", 0, None), track(2, "a/b", 100, None)]);
+        assert_eq!(found.len(), 2, "{:?}", found);
+    }
+}
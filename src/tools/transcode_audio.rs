@@ -1,155 +1,534 @@
-use anyhow::{ensure, Context, Result};
-use simplelog::{error, warn};
-use std::{fs, path::Path};
+use anyhow::{bail, ensure, Context, Result};
+use glob::{MatchOptions, Pattern};
+use simplelog::{info, warn};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{path_to_str, utils};
+use crate::{
+    config, global_args,
+    jobs::{self, AfterHook, Job, JobQueue},
+    path_to_str,
+    plan::{self, Action},
+    probe::{self, CodecType},
+    tools::loudness,
+    utils, WalkArgs,
+};
+
+pub use self::codecs::is_lossless;
+pub use self::companions::{CompanionPolicy, Companions};
+pub use self::convert::{Conversion, Dither, Downmix, DownmixMatrix};
+pub use self::cover::CoverArt;
+pub use self::guard::LossyPolicy;
+pub use self::normalize::Normalize;
+use self::sync::{SyncState, SyncStatus};
+use self::{
+    codecs::{Codec, RateControl},
+    cover::CoverArgs,
+    guard::SourceGuard,
+    normalize::Normalizer,
+};
+
+mod codecs;
+mod companions;
+mod convert;
+mod cover;
+mod guard;
+mod normalize;
+pub mod recompress;
+mod sync;
+
+/// How each file should be transcoded.
+struct Settings<'a> {
+    /// The bitrate, VBR quality or compression level options.
+    rate_args: Vec<String>,
+    /// The FFmpeg encoder.
+    codec: &'a str,
+    container: &'a str,
+    /// Encoder options and extra arguments from the profile.
+    extra_args: Vec<String>,
+    /// Normalizes the loudness of each file, if set.
+    normalizer: Option<Normalizer>,
+    /// Limits the sample rate, bit depth and channels, if set.
+    conversion: Option<Conversion>,
+    /// Whether the codec is lossless, so it has a bit depth.
+    lossless: bool,
+    /// Decides which sources shouldn't be transcoded.
+    guard: SourceGuard,
+    /// How to embed cover art, if at all.
+    cover: Option<CoverArt>,
+    /// Whether to overwrite existing files. Files changed since they were last synced are
+    /// overwritten regardless.
+    overwrite: bool,
+}
+
+/// How files are transcoded, as given on the command line.
+pub struct TranscodeOptions<'a> {
+    pub bitrate: Option<&'a str>,
+    pub vbr: Option<f32>,
+    pub compression: Option<u8>,
+    pub codec: Option<&'a str>,
+    pub container: Option<&'a str>,
+    pub profile: Option<&'a str>,
+    pub sync: bool,
+    pub sync_hash: bool,
+    pub delete_orphans: bool,
+    pub companions: Companions,
+    pub cover: Option<CoverArt>,
+    pub lossy_source: LossyPolicy,
+    pub allow_upscale: bool,
+    pub replaygain: bool,
+    pub normalize: Option<Normalize>,
+    pub conversion: Conversion,
+    pub overwrite: bool,
+}
 
 pub fn run(
     src_path: &Path,
     src_container: &str,
     dest_path: &Path,
-    bitrate: &str,
-    codec: &str,
-    container: &str,
-    overwrite: bool,
+    options: TranscodeOptions,
     qffmpeg: bool,
 ) -> Result<()> {
-    if !validate_params(bitrate, codec, container) {
-        return Ok(());
+    let TranscodeOptions {
+        bitrate,
+        vbr,
+        compression,
+        codec,
+        container,
+        profile,
+        sync,
+        sync_hash,
+        delete_orphans,
+        companions,
+        cover,
+        lossy_source,
+        allow_upscale,
+        replaygain,
+        normalize,
+        conversion,
+        overwrite,
+    } = options;
+    let profile = config::load_profile(profile)?;
+    let codec = codec
+        .or(profile.encoder.as_deref())
+        .context("No codec given! Pass one or use a profile that sets 'encoder'.")?;
+    let container = container
+        .or(profile.container.as_deref())
+        .context("No container given! Pass one or use a profile that sets 'container'.")?;
+    let bitrate = bitrate.or(profile.bitrate.as_deref());
+
+    let registered = Codec::find(codec)?;
+    registered.validate(container)?;
+    let rate = match (vbr, compression) {
+        (Some(level), _) => RateControl::Vbr(level),
+        (_, Some(level)) => RateControl::Compression(level.into()),
+        _ => RateControl::Bitrate(bitrate),
+    };
+    let rate_args = registered.rate_args(rate)?;
+    let target_kbps = match rate {
+        RateControl::Bitrate(Some(bitrate)) if !registered.lossless => {
+            Some(codecs::parse_bitrate(bitrate)?)
+        }
+        _ => None,
+    };
+    let encoder = registered.resolve_encoder(codec)?;
+    if encoder == "aac" {
+        warn!("You should use 'libfdk_aac' instead of 'aac' for better quality!");
+    }
+    let mut extra_args = [profile.params_args(&[]), profile.extra_args.clone()].concat();
+    if Codec::is_experimental(encoder) {
+        warn!(
+            "Using FFmpeg's experimental {} encoder since it doesn't have a better one.",
+            encoder
+        );
+        extra_args.extend(["-strict".to_string(), "experimental".to_string()]);
+    }
+
+    let mut conversion = conversion;
+    if let (Some(depth @ 17..), Some(_)) = (conversion.bit_depth, conversion.dither) {
+        warn!(
+            "Dithering only applies when reducing to 16 bits, so '--dither' will be ignored. \
+             Reducing to {} bits truncates the samples instead, which is far below what can be \
+             heard.",
+            depth
+        );
+    }
+    if conversion.bit_depth.is_some() {
+        if !registered.lossless {
+            warn!(
+                "{} is lossy, so it has no bit depth and '--bit-depth' will be ignored.",
+                registered.name
+            );
+            conversion.bit_depth = None;
+        } else if registered.name == "pcm" {
+            warn!(
+                "The PCM encoder sets the bit depth, so '--bit-depth' will be ignored. Pick one \
+                 like 'pcm_s16le' or 'pcm_s24le' instead."
+            );
+            conversion.bit_depth = None;
+        }
+    }
+    if conversion.downmix == Some(Downmix::Mono) && conversion.matrix != DownmixMatrix::Default {
+        warn!("Downmix matrices only apply when downmixing to stereo.");
     }
+
+    let settings = Settings {
+        rate_args,
+        normalizer: normalize.map(Normalizer::load).transpose()?,
+        conversion: conversion.is_set().then_some(conversion),
+        lossless: registered.lossless,
+        guard: SourceGuard::new(lossy_source, allow_upscale, registered, target_kbps),
+        codec: encoder,
+        container,
+        extra_args,
+        cover,
+        overwrite,
+    };
+
+    let sync = if sync {
+        if src_path.is_file() {
+            bail!("Syncing only works when transcoding a directory!");
+        }
+        Some(SyncState::load(src_path, dest_path, sync_hash)?)
+    } else {
+        None
+    };
+
+    let src_filter = SourceFilter::parse(src_container)?;
+    let mut queue = JobQueue::new(qffmpeg);
     process_dir(
+        &mut queue,
         src_path,
-        src_container,
+        &src_filter,
         dest_path,
-        bitrate,
-        codec,
-        container,
+        &settings,
+        &companions,
+        sync.as_ref(),
+    )?;
+    if let Some(normalizer) = &settings.normalizer {
+        normalizer.save()?;
+    }
+
+    // Albums are only tagged once all of their tracks are done.
+    let album_dirs = if replaygain {
+        queue
+            .outputs()
+            .filter_map(Path::parent)
+            .map(Path::to_path_buf)
+            .collect::<BTreeSet<_>>()
+    } else {
+        BTreeSet::new()
+    };
+
+    let result = queue.run();
+    if let Some(sync) = &sync {
+        if delete_orphans {
+            sync.delete_orphans()?;
+        }
+        // Save even if some jobs failed so the ones that didn't aren't redone next time.
+        sync.save()?;
+    }
+    result?;
+
+    if album_dirs.is_empty() {
+        return Ok(());
+    }
+    loudness::tag_dirs(
+        &album_dirs.into_iter().collect::<Vec<_>>(),
+        settings.container,
         qffmpeg,
-        overwrite,
-        false,
     )
 }
 
+/// Queues every matching file in `src_path` to be transcoded into the same relative path in
+/// `dest_path`, along with any companion files. If `src_path` is a file, `dest_path` is the file to
+/// transcode it to.
 fn process_dir(
+    queue: &mut JobQueue,
     src_path: &Path,
-    src_container: &str,
+    src_filter: &SourceFilter,
     dest_path: &Path,
-    bitrate: &str,
-    codec: &str,
-    container: &str,
-    qffmpeg: bool,
-    overwrite: bool,
-    nested: bool,
+    settings: &Settings,
+    companions: &Companions,
+    sync: Option<&SyncState>,
 ) -> Result<()> {
     if src_path.is_file() {
-        transcode_file(
-            src_path, dest_path, bitrate, codec, container, overwrite, qffmpeg,
-        )?;
-        return Ok(());
+        return queue_transcodes(
+            queue,
+            vec![Pending {
+                src: src_path.to_path_buf(),
+                dest: dest_path.to_path_buf(),
+                overwrite: settings.overwrite,
+                hook: None,
+            }],
+            settings,
+        );
     }
 
-    fs::create_dir_all(dest_path)?;
+    plan::create_dir_all(dest_path)?;
 
-    for path in utils::read_dir(src_path, |_| true)? {
-        let strip_prefix = if nested {
-            src_path.parent().context("no parent?")?
-        } else {
-            src_path
-        };
-        let rel_path = path.strip_prefix(strip_prefix)?;
-        let out_path = dest_path.join(rel_path);
-
-        if path.is_dir() {
-            process_dir(
-                &path,
-                src_container,
-                dest_path,
-                bitrate,
-                codec,
-                container,
-                overwrite,
-                qffmpeg,
-                true,
-            )?;
-        } else {
-            if path.extension().unwrap_or_default().to_string_lossy() != src_container {
-                continue;
+    let walk = WalkArgs {
+        recursive: true,
+        ..global_args().walk.clone()
+    };
+    let mut up_to_date = 0;
+    let mut pending = Vec::new();
+    let paths = utils::walk_dir(src_path, &walk, |p| {
+        companions.matches(p) || src_filter.matches(p)
+    })?;
+    let (companion_paths, candidates) = paths
+        .into_iter()
+        .partition::<Vec<_>, _>(|p| companions.matches(p));
+    for path in companion_paths {
+        let out_path = dest_path.join(path.strip_prefix(src_path)?);
+        // When syncing, companions are only replaced if the source is newer.
+        let overwrite = settings.overwrite
+            || (sync.is_some() && out_path.exists() && is_newer(&path, &out_path)?);
+        companions.process(queue, &path, &out_path, overwrite)?;
+    }
+    for path in src_filter.select(candidates) {
+        let rel_path = path.strip_prefix(src_path)?;
+        let out_path = dest_path.join(rel_path).with_extension(settings.container);
+
+        let (hook, overwrite) = match sync {
+            None => (None, settings.overwrite),
+            Some(sync) => {
+                let (stamp, overwrite) = match sync.check(&path, &out_path)? {
+                    SyncStatus::UpToDate => {
+                        up_to_date += 1;
+                        if plan::is_dry_run() {
+                            plan::record(Action::Skip {
+                                path: out_path,
+                                reason: "it's up to date".to_string(),
+                            });
+                        }
+                        continue;
+                    }
+                    SyncStatus::New(stamp) => (stamp, false),
+                    SyncStatus::Changed(stamp) => (stamp, true),
+                };
+                let hook: AfterHook = Box::new(sync.record_hook(&path, &out_path, stamp)?);
+                (Some(hook), overwrite)
             }
-            if let Some(parent_dir) = out_path.parent() {
-                fs::create_dir_all(parent_dir)?;
+        };
+        pending.push(Pending {
+            src: path,
+            dest: out_path,
+            overwrite,
+            hook,
+        });
+    }
+    if sync.is_some() {
+        info!("{} file(s) are already up to date.", up_to_date);
+    }
+    queue_transcodes(queue, pending, settings)
+}
+
+/// A file that's going to be transcoded.
+struct Pending {
+    src: PathBuf,
+    dest: PathBuf,
+    overwrite: bool,
+    /// Runs once the file has been transcoded.
+    hook: Option<AfterHook>,
+}
+
+/// Queues the jobs that transcode the files. Any measuring or probing needed to make the jobs is
+/// done for all of them at once, so it can run in parallel.
+fn queue_transcodes(
+    queue: &mut JobQueue,
+    pending: Vec<Pending>,
+    settings: &Settings,
+) -> Result<()> {
+    let checks = jobs::parallel_map(&pending, |p| settings.guard.check(&p.src));
+    let mut checked = Vec::with_capacity(pending.len());
+    for (file, check) in pending.into_iter().zip(checks) {
+        match check? {
+            Some(reason) => queue.skip(&file.src, reason),
+            None => checked.push(file),
+        }
+    }
+    let pending = checked;
+
+    let audio_args = if settings.normalizer.is_some() || settings.conversion.is_some() {
+        if settings.normalizer.is_some() {
+            if plan::is_dry_run() {
+                info!(
+                    "Not measuring loudness in a dry run, so 'MEASURED' stands in for \
+                     measurements that aren't cached yet."
+                );
+            } else {
+                info!("Measuring the loudness of {} file(s)...", pending.len());
             }
-            transcode_file(
-                &path, &out_path, bitrate, codec, container, overwrite, qffmpeg,
-            )?;
         }
+        jobs::parallel_map(&pending, |p| audio_args(&p.src, settings))
+    } else {
+        pending.iter().map(|_| Ok(Vec::new())).collect()
+    };
+    for (file, audio_args) in pending.into_iter().zip(audio_args) {
+        let audio_args = match audio_args {
+            Ok(args) => args,
+            Err(e) => {
+                queue.skip(&file.src, format!("{:#}", e));
+                continue;
+            }
+        };
+        let job = transcode_file(&file.src, &file.dest, settings, file.overwrite, &audio_args)?;
+        queue.push(match file.hook {
+            Some(hook) => job.then(hook),
+            None => job,
+        });
     }
     Ok(())
 }
 
-fn validate_params(bitrate: &str, codec: &str, container: &str) -> bool {
-    if !bitrate.ends_with("k") && bitrate.parse::<usize>().unwrap_or_default() < 1000 {
-        warn!(
-            "Bitrate {} seems too low, did you mean {}k?",
-            bitrate, bitrate
-        );
+/// Gets the filters and output options that normalize and convert the audio of `src`.
+fn audio_args(src: &Path, settings: &Settings) -> Result<Vec<String>> {
+    let mut filters = Vec::new();
+    let mut args = Vec::new();
+    if let Some(normalizer) = &settings.normalizer {
+        // The conversion restores the sample rate itself, so it isn't resampled twice.
+        filters.push(normalizer.filter(src, settings.conversion.is_none())?);
     }
-    if codec == "aac" || codec == "libfdk_aac" {
-        if container != "m4a" && container != "mkv" {
-            error!("The AAC codec can only be in an m4a or mkv container!");
-            return false;
-        }
-        if codec == "aac" {
-            warn!("You should use 'libfdk_acc' instead of 'aac' for better quality!");
+    if let Some(conversion) = &settings.conversion {
+        let info = probe::probe(src)?;
+        let stream = info
+            .streams
+            .iter()
+            .find(|s| s.codec_type == CodecType::Audio)
+            .context("It has no audio stream")?;
+        let (filter, output_args) =
+            conversion.args(stream, settings.lossless, settings.normalizer.is_some());
+        filters.extend(filter);
+        args.extend(output_args);
+    }
+    if !filters.is_empty() {
+        args.splice(0..0, ["-filter:a".to_string(), filters.join(",")]);
+    }
+    Ok(args)
+}
+
+/// Whether `a` was modified after `b`.
+fn is_newer(a: &Path, b: &Path) -> Result<bool> {
+    Ok(fs::metadata(a)?.modified()? > fs::metadata(b)?.modified()?)
+}
+
+/// Which files in the source directory should be transcoded.
+enum SourceFilter {
+    /// Files whose extension matches any of the patterns, ignoring case.
+    Extensions(Vec<Pattern>),
+    /// Any file ffprobe says is audio, i.e. has an audio stream and no video besides cover art.
+    Auto,
+}
+
+impl SourceFilter {
+    fn parse(raw: &str) -> Result<Self> {
+        if raw.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
         }
-    } else if codec == "mp3" {
-        if container != "mp3" {
-            error!("The MP3 codec can only be placed in an mp3 container.");
-            return false;
+        let patterns = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| {
+                Pattern::new(ext.trim_start_matches('.'))
+                    .with_context(|| format!("Invalid source container '{}'", ext))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(!patterns.is_empty(), "No source containers given!");
+        Ok(Self::Extensions(patterns))
+    }
+
+    /// Whether a file could be a source. With `Auto`, any file could be until it's probed by
+    /// [`Self::select`].
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Self::Extensions(patterns) => {
+                let ext = path.extension().unwrap_or_default().to_string_lossy();
+                let options = MatchOptions {
+                    case_sensitive: false,
+                    ..MatchOptions::new()
+                };
+                patterns.iter().any(|p| p.matches_with(&ext, options))
+            }
+            Self::Auto => true,
         }
-    } else if codec == "opus" {
-        if container != "ogg" {
-            error!("The opus codec can only be placed in an ogg container.");
-            return false;
+    }
+
+    /// Keeps the files that really are sources. With `Auto`, this probes every file, so it's done
+    /// in parallel.
+    fn select(&self, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        match self {
+            Self::Extensions(_) => paths,
+            Self::Auto => {
+                let is_audio = jobs::parallel_map(&paths, |p| {
+                    probe::probe(p).is_ok_and(|info| info.is_audio())
+                });
+                paths
+                    .into_iter()
+                    .zip(is_audio)
+                    .filter_map(|(path, is_audio)| is_audio.then_some(path))
+                    .collect()
+            }
         }
-    } else {
-        error!("Unsupported codec '{}'!", codec);
     }
-    true
 }
 
 fn transcode_file(
     src: &Path,
     dest: &Path,
-    bitrate: &str,
-    codec: &str,
-    container: &str,
+    settings: &Settings,
     overwrite: bool,
-    qffmpeg: bool,
-) -> Result<()> {
+    audio_args: &[String],
+) -> Result<Job> {
     ensure!(src.is_file(), "transcode_file does not accept directories!");
 
+    let cover = match &settings.cover {
+        Some(cover) => cover.args(src, settings.container)?,
+        None => CoverArgs::default(),
+    };
+
     let mut args = vec![
         if overwrite { "-y" } else { "-n" },
         "-i",
         path_to_str!(src)?,
-        "-acodec",
-        codec,
-        "-ab",
-        bitrate,
+    ];
+    args.extend(cover.inputs.iter().map(String::as_str));
+    args.extend(["-acodec", settings.codec]);
+    args.extend(settings.rate_args.iter().map(String::as_str));
+    args.extend(audio_args.iter().map(String::as_str));
+    args.extend([
         "-map_metadata",
-        "0",
+        cover.metadata_input(),
         "-id3v2_version",
         "3",
-    ];
+    ]);
 
     // Codec-specific args
-    if codec.contains("aac") {
+    if settings.codec.contains("aac") && settings.cover.is_none() {
         args.push("-vn");
     }
 
+    args.extend(cover.outputs.iter().map(String::as_str));
+    args.extend(settings.extra_args.iter().map(String::as_str));
+
     // Last arg must be the output file
-    let dest_path = dest.with_extension(container);
+    let dest_path = dest.with_extension(settings.container);
     args.push(path_to_str!(dest_path)?);
 
-    utils::run_ffmpeg(qffmpeg, args)
+    let job = Job::new(src.display().to_string(), &dest_path, args);
+    Ok(match cover.metadata_file {
+        Some(file) => {
+            // Written only when the job starts, since it holds the whole cover.
+            let file = Arc::new(file);
+            let cleanup = Arc::clone(&file);
+            job.before(move || file.write())
+                .finally(move || cleanup.remove())
+        }
+        None => job,
+    })
 }
@@ -0,0 +1,503 @@
+use std::{collections::BTreeSet, ops::RangeInclusive};
+
+use anyhow::{bail, Context, Result};
+use simplelog::warn;
+
+use crate::utils;
+
+/// An audio codec, and what it can be used with.
+pub struct Codec {
+    pub name: &'static str,
+    /// The FFmpeg encoders for the codec, best first. Each is also accepted as the codec's name.
+    pub encoders: &'static [&'static str],
+    /// The containers (file extensions) the codec can be placed in.
+    pub containers: &'static [&'static str],
+    /// The bitrates that make sense for the codec, in kbit/s. `None` if it has no bitrate.
+    pub bitrate: Option<RangeInclusive<u32>>,
+    /// The codec's VBR quality scale, if it has one.
+    pub vbr: Option<Scale>,
+    /// The codec's compression levels, if it has them. They only affect the size and speed.
+    pub compression: Option<Scale>,
+    pub lossless: bool,
+}
+
+/// A setting of an encoder that takes a number, like its VBR quality.
+pub struct Scale {
+    /// The FFmpeg option, e.g. '-q:a'.
+    pub option: &'static str,
+    pub range: RangeInclusive<f32>,
+    /// Whether the option only takes whole numbers.
+    pub integer: bool,
+    /// Explains which way the scale goes, e.g. '0 is best'.
+    pub hint: &'static str,
+}
+
+/// How the size and quality of the output is controlled.
+#[derive(Debug, Clone, Copy)]
+pub enum RateControl<'a> {
+    /// A target bitrate, e.g. '128k'. Only a CBR bitrate if the encoder doesn't default to VBR.
+    Bitrate(Option<&'a str>),
+    /// A quality level on the encoder's VBR scale.
+    Vbr(f32),
+    /// A compression level for lossless codecs.
+    Compression(f32),
+}
+
+pub const CODECS: &[Codec] = &[
+    Codec {
+        name: "flac",
+        encoders: &["flac"],
+        containers: &["flac", "ogg", "oga", "mka", "mkv"],
+        bitrate: None,
+        vbr: None,
+        compression: Some(Scale {
+            option: "-compression_level",
+            range: 0.0..=12.0,
+            integer: true,
+            hint: "higher is smaller but slower",
+        }),
+        lossless: true,
+    },
+    Codec {
+        name: "alac",
+        encoders: &["alac"],
+        containers: &["m4a", "m4b", "mp4", "caf", "mka", "mkv"],
+        bitrate: None,
+        vbr: None,
+        compression: Some(Scale {
+            option: "-compression_level",
+            range: 0.0..=2.0,
+            integer: true,
+            hint: "higher is smaller but slower",
+        }),
+        lossless: true,
+    },
+    Codec {
+        name: "wavpack",
+        encoders: &["wavpack"],
+        containers: &["wv", "mka", "mkv"],
+        bitrate: None,
+        vbr: None,
+        compression: Some(Scale {
+            option: "-compression_level",
+            range: 0.0..=8.0,
+            integer: true,
+            hint: "higher is smaller but slower",
+        }),
+        lossless: true,
+    },
+    Codec {
+        name: "pcm",
+        encoders: &[
+            "pcm_s16le",
+            "pcm_s24le",
+            "pcm_s32le",
+            "pcm_f32le",
+            "pcm_s16be",
+            "pcm_s24be",
+        ],
+        containers: &["wav", "w64", "aiff", "aif", "caf", "mka", "mkv"],
+        bitrate: None,
+        vbr: None,
+        compression: None,
+        lossless: true,
+    },
+    Codec {
+        name: "opus",
+        encoders: &["libopus", "opus"],
+        containers: &["opus", "ogg", "oga", "caf", "mka", "mkv", "webm"],
+        bitrate: Some(6..=510),
+        vbr: None,
+        compression: None,
+        lossless: false,
+    },
+    Codec {
+        name: "vorbis",
+        encoders: &["libvorbis", "vorbis"],
+        containers: &["ogg", "oga", "mka", "mkv", "webm"],
+        bitrate: Some(45..=500),
+        vbr: Some(Scale {
+            option: "-q:a",
+            range: -1.0..=10.0,
+            integer: false,
+            hint: "higher is better",
+        }),
+        compression: None,
+        lossless: false,
+    },
+    Codec {
+        name: "mp3",
+        encoders: &["libmp3lame"],
+        containers: &["mp3", "mka", "mkv"],
+        bitrate: Some(8..=320),
+        vbr: Some(Scale {
+            option: "-q:a",
+            range: 0.0..=9.0,
+            integer: false,
+            hint: "0 is best, like LAME's -V",
+        }),
+        compression: None,
+        lossless: false,
+    },
+    Codec {
+        name: "libfdk_aac",
+        encoders: &["libfdk_aac"],
+        containers: &["m4a", "m4b", "mp4", "aac", "mka", "mkv"],
+        bitrate: Some(8..=512),
+        vbr: Some(Scale {
+            option: "-vbr",
+            range: 1.0..=5.0,
+            integer: true,
+            hint: "higher is better",
+        }),
+        compression: None,
+        lossless: false,
+    },
+    Codec {
+        name: "aac",
+        encoders: &["aac"],
+        containers: &["m4a", "m4b", "mp4", "aac", "mka", "mkv"],
+        bitrate: Some(8..=512),
+        vbr: Some(Scale {
+            option: "-q:a",
+            range: 0.1..=2.0,
+            integer: false,
+            hint: "higher is better",
+        }),
+        compression: None,
+        lossless: false,
+    },
+];
+
+/// Lossless codecs FFmpeg can decode that aren't in the registry since they can't be encoded to.
+const OTHER_LOSSLESS: &[&str] = &["ape", "tta", "tak", "mlp", "truehd"];
+
+/// Whether a codec, as named by ffprobe, is lossless.
+pub fn is_lossless(codec_name: &str) -> bool {
+    codec_name.starts_with("pcm_")
+        || OTHER_LOSSLESS.contains(&codec_name)
+        || CODECS.iter().any(|c| c.lossless && c.name == codec_name)
+}
+
+impl Codec {
+    /// Finds a codec by its name or the name of one of its encoders, ignoring case.
+    pub fn find(name: &str) -> Result<&'static Self> {
+        let found = CODECS.iter().find(|c| {
+            c.name.eq_ignore_ascii_case(name)
+                || c.encoders.iter().any(|e| e.eq_ignore_ascii_case(name))
+        });
+        if let Some(codec) = found {
+            return Ok(codec);
+        }
+        let names = CODECS.iter().map(|c| c.name).collect::<Vec<_>>();
+        match closest(name, names.iter().copied()) {
+            Some(suggestion) => bail!(
+                "Unsupported codec '{}'! Did you mean '{}'? Supported codecs are: {}",
+                name,
+                suggestion,
+                names.join(", ")
+            ),
+            None => bail!(
+                "Unsupported codec '{}'! Supported codecs are: {}",
+                name,
+                names.join(", ")
+            ),
+        }
+    }
+
+    /// Checks that the codec can be placed in `container`.
+    pub fn validate(&self, container: &str) -> Result<()> {
+        if !self
+            .containers
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(container))
+        {
+            let fitting = CODECS
+                .iter()
+                .filter(|c| {
+                    c.containers
+                        .iter()
+                        .any(|c| c.eq_ignore_ascii_case(container))
+                })
+                .map(|c| c.name)
+                .collect::<Vec<_>>();
+            let hint = if fitting.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    " A '{}' container can hold {}.",
+                    container,
+                    fitting.join(", ")
+                )
+            };
+            bail!(
+                "The {} codec can't be placed in a '{}' container! Use one of: {}.{}",
+                self.name,
+                container,
+                self.containers.join(", "),
+                hint
+            );
+        }
+        Ok(())
+    }
+
+    /// Gets the FFmpeg arguments for the rate control, checking that it makes sense for the codec.
+    pub fn rate_args(&self, rate: RateControl) -> Result<Vec<String>> {
+        let bitrate = match rate {
+            RateControl::Bitrate(bitrate) => bitrate,
+            RateControl::Vbr(level) => {
+                let scale = self.vbr.as_ref().with_context(|| {
+                    if self.lossless {
+                        format!(
+                            "{} is lossless, so it has no VBR quality levels!",
+                            self.name
+                        )
+                    } else {
+                        format!(
+                            "{} has no VBR quality levels! Give it a bitrate instead.",
+                            self.name
+                        )
+                    }
+                })?;
+                return scale.args("VBR quality", self.name, level);
+            }
+            RateControl::Compression(level) => {
+                let scale = self
+                    .compression
+                    .as_ref()
+                    .with_context(|| format!("{} has no compression levels!", self.name))?;
+                return scale.args("compression level", self.name, level);
+            }
+        };
+
+        match (&self.bitrate, bitrate) {
+            (None, Some(bitrate)) => {
+                warn!(
+                    "{} is lossless, so the bitrate {} will be ignored.",
+                    self.name, bitrate
+                )
+            }
+            (Some(_), None) => bail!(
+                "No bitrate given! {} is lossy, so it needs one. Pass one, use '--vbr' or use a \
+                 profile that sets 'bitrate'.",
+                self.name
+            ),
+            (Some(range), Some(bitrate)) => {
+                let kbps = parse_bitrate(bitrate)?;
+                if !range.contains(&kbps) {
+                    let hint = match bitrate.parse::<u32>() {
+                        Ok(n) if range.contains(&n) => format!(" Did you mean {}k?", n),
+                        _ => String::new(),
+                    };
+                    bail!(
+                        "A bitrate of {} doesn't make sense for {}, it must be between {}k and \
+                         {}k!{}",
+                        bitrate,
+                        self.name,
+                        range.start(),
+                        range.end(),
+                        hint
+                    );
+                }
+            }
+            (None, None) => {}
+        }
+        Ok(match bitrate.filter(|_| self.bitrate.is_some()) {
+            Some(bitrate) => vec!["-ab".to_string(), bitrate.to_string()],
+            None => vec![],
+        })
+    }
+
+    /// Picks the encoder to use. If a specific encoder was requested it has to be available,
+    /// otherwise the best available one is used.
+    pub fn resolve_encoder(&self, requested: &str) -> Result<&'static str> {
+        let available = available_encoders()?;
+        let requested_encoder = self
+            .encoders
+            .iter()
+            .find(|e| e.eq_ignore_ascii_case(requested) && !e.eq_ignore_ascii_case(self.name));
+        if let Some(encoder) = requested_encoder {
+            if !available.contains(*encoder) {
+                bail!(
+                    "Your FFmpeg doesn't have the {} encoder! Use '{}' to pick whichever {} \
+                     encoder it does have.",
+                    encoder,
+                    self.name,
+                    self.name
+                );
+            }
+            return Ok(encoder);
+        }
+        self.encoders
+            .iter()
+            .find(|e| available.contains(**e))
+            .copied()
+            .with_context(|| {
+                format!(
+                    "Your FFmpeg doesn't have an encoder for {}! It needs one of: {}",
+                    self.name,
+                    self.encoders.join(", ")
+                )
+            })
+    }
+
+    /// Whether FFmpeg considers the encoder experimental, so it has to be enabled explicitly.
+    pub fn is_experimental(encoder: &str) -> bool {
+        matches!(encoder, "opus" | "vorbis")
+    }
+}
+
+impl Scale {
+    fn args(&self, what: &str, codec: &str, level: f32) -> Result<Vec<String>> {
+        if self.integer && level.fract() != 0.0 {
+            bail!(
+                "{} isn't a valid {} for {}! It must be a whole number from {} to {} ({}).",
+                level,
+                what,
+                codec,
+                self.range.start(),
+                self.range.end(),
+                self.hint
+            );
+        }
+        if !self.range.contains(&level) {
+            bail!(
+                "{} isn't a valid {} for {}! It must be between {} and {} ({}).",
+                level,
+                what,
+                codec,
+                self.range.start(),
+                self.range.end(),
+                self.hint
+            );
+        }
+        Ok(vec![self.option.to_string(), level.to_string()])
+    }
+}
+
+/// Parses a bitrate like FFmpeg does, e.g. '128k' or '128000', into kbit/s.
+pub fn parse_bitrate(bitrate: &str) -> Result<u32> {
+    let (number, multiplier) = match bitrate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&bitrate[..i], 1_000.0),
+        Some((i, 'M')) => (&bitrate[..i], 1_000_000.0),
+        _ => (bitrate, 1.0),
+    };
+    let bps = number
+        .parse::<f64>()
+        .ok()
+        .filter(|n| *n > 0.0)
+        .with_context(|| format!("Invalid bitrate '{}'! Use e.g. '128k'.", bitrate))?;
+    Ok((bps * multiplier / 1_000.0).round() as u32)
+}
+
+/// Gets the names of all encoders the installed FFmpeg has.
+fn available_encoders() -> Result<BTreeSet<String>> {
+    let output = utils::ffmpeg_output(["-encoders"])?;
+    // Each encoder is listed as e.g. ' A....D libopus              libopus Opus'.
+    Ok(output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("------"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Finds the name closest to `name`, if any is close enough to be a likely typo.
+fn closest<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    names
+        .map(|n| (edit_distance(&name.to_lowercase(), n), n))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, n)| n)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bitrates() {
+        assert_eq!(parse_bitrate("128k").unwrap(), 128);
+        assert_eq!(parse_bitrate("128K").unwrap(), 128);
+        assert_eq!(parse_bitrate("96.5k").unwrap(), 97);
+        assert_eq!(parse_bitrate("1.5M").unwrap(), 1500);
+        // Without a suffix it's bit/s, like in FFmpeg.
+        assert_eq!(parse_bitrate("128000").unwrap(), 128);
+        assert_eq!(parse_bitrate("128").unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_bad_bitrates() {
+        for bitrate in ["", "k", "0k", "-128k", "128kb", "128 k", "fast"] {
+            assert!(
+                parse_bitrate(bitrate).is_err(),
+                "'{}' should be rejected",
+                bitrate
+            );
+        }
+    }
+
+    #[test]
+    fn checks_bitrates() {
+        let opus = Codec::find("libopus").unwrap();
+        assert_eq!(
+            opus.rate_args(RateControl::Bitrate(Some("128k"))).unwrap(),
+            ["-ab", "128k"]
+        );
+        // A number that's only in range as kbit/s was probably missing its 'k'.
+        let e = opus
+            .rate_args(RateControl::Bitrate(Some("128")))
+            .unwrap_err();
+        assert!(e.to_string().ends_with("Did you mean 128k?"), "{}", e);
+        let e = opus
+            .rate_args(RateControl::Bitrate(Some("2000k")))
+            .unwrap_err();
+        assert!(!e.to_string().contains("Did you mean"), "{}", e);
+        assert!(opus.rate_args(RateControl::Bitrate(None)).is_err());
+
+        // Lossless codecs ignore the bitrate.
+        let flac = Codec::find("flac").unwrap();
+        assert!(flac
+            .rate_args(RateControl::Bitrate(Some("128k")))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn finds_close_names() {
+        let names = ["opus", "vorbis", "mp3", "aac", "flac"];
+        assert_eq!(closest("opsu", names.into_iter()), Some("opus"));
+        assert_eq!(closest("FLAK", names.into_iter()), Some("flac"));
+        assert_eq!(closest("mp4", names.into_iter()), Some("mp3"));
+        assert_eq!(closest("wma", names.into_iter()), None);
+        assert_eq!(closest("anything", [].into_iter()), None);
+
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+
+    #[test]
+    fn suggests_codecs() {
+        assert_eq!(Codec::find("LIBMP3LAME").unwrap().name, "mp3");
+        let e = Codec::find("opsu").err().unwrap();
+        assert!(e.to_string().contains("Did you mean 'opus'?"), "{}", e);
+        let e = Codec::find("something").err().unwrap();
+        assert!(!e.to_string().contains("Did you mean"), "{}", e);
+    }
+}
@@ -0,0 +1,160 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    jobs::{Job, JobQueue},
+    path_to_str,
+    plan::{self, Action},
+};
+
+use super::cover::scale_filter;
+
+/// Image formats that can be downscaled or recompressed.
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "bmp"];
+
+/// What to do with files in the source directory that aren't transcoded, like cover art or lyrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompanionPolicy {
+    /// Leave them out of the destination.
+    Skip,
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+impl CompanionPolicy {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Copy => "copy",
+            Self::Hardlink => "hardlink",
+            Self::Symlink => "symlink",
+        }
+    }
+}
+
+/// How companion files are brought over to the destination.
+pub struct Companions {
+    pub policy: CompanionPolicy,
+    /// Only files with these extensions (lowercase, without the dot) are companions.
+    pub extensions: Vec<String>,
+    /// Downscale images so neither side is larger than this many pixels.
+    pub cover_max_size: Option<u32>,
+    /// Recompress JPEG images with this quality (2-31, lower is better).
+    pub cover_quality: Option<u8>,
+}
+
+impl Companions {
+    pub fn matches(&self, path: &Path) -> bool {
+        self.policy != CompanionPolicy::Skip && self.extensions.contains(&extension(path))
+    }
+
+    /// Brings `src` over to `dest`. Images that have to be resized or recompressed are queued as
+    /// FFmpeg jobs, everything else is handled right away.
+    pub fn process(
+        &self,
+        queue: &mut JobQueue,
+        src: &Path,
+        dest: &Path,
+        overwrite: bool,
+    ) -> Result<()> {
+        if self.is_processed_image(src) {
+            queue.push(self.image_job(src, dest, overwrite)?);
+            return Ok(());
+        }
+
+        if dest.exists() && !overwrite {
+            if plan::is_dry_run() {
+                plan::record(Action::Skip {
+                    path: dest.to_path_buf(),
+                    reason: "it already exists".to_string(),
+                });
+            }
+            return Ok(());
+        }
+        if let Some(parent) = dest.parent() {
+            plan::create_dir_all(parent)?;
+        }
+        if plan::is_dry_run() {
+            plan::record(Action::Copy {
+                from: src.to_path_buf(),
+                to: dest.to_path_buf(),
+                method: self.policy.name().to_string(),
+            });
+            return Ok(());
+        }
+
+        // Links can't replace an existing file.
+        if dest.exists() && self.policy != CompanionPolicy::Copy {
+            fs::remove_file(dest)
+                .with_context(|| format!("Failed to replace {}", dest.display()))?;
+        }
+        match self.policy {
+            CompanionPolicy::Skip => return Ok(()),
+            CompanionPolicy::Copy => fs::copy(src, dest).map(|_| ()),
+            CompanionPolicy::Hardlink => fs::hard_link(src, dest),
+            CompanionPolicy::Symlink => symlink(&src.canonicalize()?, dest),
+        }
+        .with_context(|| {
+            format!(
+                "Failed to {} {} to {}",
+                self.policy.name(),
+                src.display(),
+                dest.display()
+            )
+        })
+    }
+
+    fn is_processed_image(&self, path: &Path) -> bool {
+        (self.cover_max_size.is_some() || self.cover_quality.is_some())
+            && IMAGE_EXTENSIONS.contains(&extension(path).as_str())
+    }
+
+    fn image_job(&self, src: &Path, dest: &Path, overwrite: bool) -> Result<Job> {
+        let mut args = vec![
+            if overwrite { "-y" } else { "-n" }.to_string(),
+            "-i".to_string(),
+            path_to_str!(src)?.to_string(),
+        ];
+        if let Some(size) = self.cover_max_size {
+            args.push("-vf".to_string());
+            args.push(scale_filter(size));
+        }
+        if let Some(quality) = self.cover_quality {
+            if matches!(extension(src).as_str(), "jpg" | "jpeg") {
+                args.push("-q:v".to_string());
+                args.push(quality.to_string());
+            }
+        }
+        // Write a single image instead of an image sequence.
+        args.extend(["-frames:v", "1", "-update", "1"].map(String::from));
+        args.push(path_to_str!(dest)?.to_string());
+        Ok(Job::new(src.display().to_string(), dest, args))
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase()
+}
+
+#[cfg(unix)]
+fn symlink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dest)
+}
+
+#[cfg(windows)]
+fn symlink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(src, dest)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::other(
+        "Symlinks aren't supported on this platform",
+    ))
+}
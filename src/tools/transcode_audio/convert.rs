@@ -0,0 +1,183 @@
+use std::{
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+use clap::ValueEnum;
+
+use crate::probe::Stream;
+
+/// How to dither when reducing the bit depth. These are FFmpeg's names for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Dither {
+    /// Truncate without dithering.
+    None,
+    Rectangular,
+    Triangular,
+    /// Triangular with high pass. A safe default.
+    TriangularHp,
+    Lipshitz,
+    /// Noise shaping, which moves the noise to where it's hard to hear.
+    Shibata,
+}
+
+impl Dither {
+    fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Rectangular => "rectangular",
+            Self::Triangular => "triangular",
+            Self::TriangularHp => "triangular_hp",
+            Self::Lipshitz => "lipshitz",
+            Self::Shibata => "shibata",
+        }
+    }
+}
+
+/// The channel layout to downmix to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Downmix {
+    Stereo,
+    Mono,
+}
+
+impl Downmix {
+    fn channels(self) -> u32 {
+        match self {
+            Self::Stereo => 2,
+            Self::Mono => 1,
+        }
+    }
+}
+
+/// How the channels are mixed together when downmixing to stereo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DownmixMatrix {
+    /// FFmpeg's standard matrix.
+    Default,
+    /// Dolby Surround compatible.
+    Dolby,
+    /// Dolby Pro Logic II compatible.
+    Dplii,
+}
+
+/// Limits on the format of the transcoded audio. Each one only applies to sources that exceed it.
+#[derive(Debug, Clone, Copy)]
+pub struct Conversion {
+    /// The highest sample rate in Hz.
+    pub sample_rate: Option<u32>,
+    /// The highest bit depth: 16, 24 or 32. Only lossless codecs have one.
+    pub bit_depth: Option<u8>,
+    /// How to dither when reducing to 16 bits, if not the default.
+    pub dither: Option<Dither>,
+    pub downmix: Option<Downmix>,
+    pub matrix: DownmixMatrix,
+}
+
+impl Conversion {
+    /// Whether any limit is set.
+    pub fn is_set(&self) -> bool {
+        self.sample_rate.is_some() || self.bit_depth.is_some() || self.downmix.is_some()
+    }
+
+    /// Gets the filter and output options that bring `stream` within the limits. The sample rate
+    /// is always set if `restore_rate` is, for when an earlier filter has changed it.
+    pub fn args(
+        &self,
+        stream: &Stream,
+        lossless: bool,
+        restore_rate: bool,
+    ) -> (Option<String>, Vec<String>) {
+        let mut options = Vec::new();
+        let mut output_args = Vec::new();
+
+        let source_rate = stream.sample_rate.unwrap_or_default();
+        let rate = self
+            .sample_rate
+            .filter(|max| source_rate > *max)
+            .or((restore_rate && source_rate > 0).then_some(source_rate));
+        if let Some(rate) = rate {
+            if has_soxr() {
+                options.push("resampler=soxr".to_string());
+            }
+            options.push(format!("osr={}", rate));
+        }
+
+        if let (Some(depth), true) = (self.bit_depth, lossless) {
+            if source_bit_depth(stream).is_none_or(|source| source > depth) {
+                if depth == 16 {
+                    let dither = self.dither.unwrap_or(Dither::TriangularHp);
+                    options.push("osf=s16".to_string());
+                    options.push(format!("dither_method={}", dither.name()));
+                    output_args.extend(["-sample_fmt".to_string(), "s16".to_string()]);
+                } else {
+                    // FFmpeg only dithers when the sample format shrinks, so 24-bit audio is
+                    // carried as s32 and the encoder truncates it.
+                    options.push("osf=s32".to_string());
+                    output_args.extend([
+                        "-sample_fmt".to_string(),
+                        "s32".to_string(),
+                        "-bits_per_raw_sample".to_string(),
+                        depth.to_string(),
+                    ]);
+                }
+            }
+        }
+
+        if let Some(downmix) = self.downmix {
+            if stream.channels.unwrap_or_default() > downmix.channels() {
+                options.push(format!(
+                    "ocl={}",
+                    match downmix {
+                        Downmix::Stereo => "stereo",
+                        Downmix::Mono => "mono",
+                    }
+                ));
+                match (downmix, self.matrix) {
+                    (Downmix::Stereo, DownmixMatrix::Dolby) => {
+                        options.push("matrix_encoding=dolby".to_string())
+                    }
+                    (Downmix::Stereo, DownmixMatrix::Dplii) => {
+                        options.push("matrix_encoding=dplii".to_string())
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if options.is_empty() {
+            (None, output_args)
+        } else {
+            (
+                Some(format!("aresample={}", options.join(":"))),
+                output_args,
+            )
+        }
+    }
+}
+
+/// Gets the bit depth of a stream, or `None` if its samples are floating point.
+fn source_bit_depth(stream: &Stream) -> Option<u8> {
+    if let Some(bits) = stream.bits_per_raw_sample.filter(|b| *b > 0) {
+        return u8::try_from(bits).ok();
+    }
+    match stream.sample_fmt.as_deref()?.trim_end_matches('p') {
+        "u8" => Some(8),
+        "s16" => Some(16),
+        "s32" => Some(32),
+        "s64" => Some(64),
+        _ => None,
+    }
+}
+
+/// Whether FFmpeg was built with the SoX resampler, which sounds better than its own.
+fn has_soxr() -> bool {
+    static HAS_SOXR: OnceLock<bool> = OnceLock::new();
+    *HAS_SOXR.get_or_init(|| {
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-version"])
+            .stdin(Stdio::null())
+            .output()
+            .is_ok_and(|o| String::from_utf8_lossy(&o.stdout).contains("--enable-libsoxr"))
+    })
+}
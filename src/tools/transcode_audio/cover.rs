@@ -0,0 +1,330 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use simplelog::warn;
+
+use crate::{
+    path_to_str,
+    probe::{self, CodecType, Tags},
+    utils,
+};
+
+/// The FLAC picture type of a front cover.
+const FRONT_COVER: u32 = 3;
+
+/// Used to give every temporary file a unique name.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How cover art is stored in a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    /// As a video stream with the 'attached_pic' disposition.
+    AttachedPic,
+    /// As a base64 FLAC picture block in the METADATA_BLOCK_PICTURE Vorbis comment.
+    BlockPicture,
+}
+
+impl Method {
+    fn for_container(container: &str) -> Option<Self> {
+        match container.to_lowercase().as_str() {
+            "mp3" | "m4a" | "m4b" | "mp4" | "flac" => Some(Self::AttachedPic),
+            "ogg" | "oga" | "opus" => Some(Self::BlockPicture),
+            _ => None,
+        }
+    }
+}
+
+/// Where the cover of a file comes from.
+#[derive(Clone)]
+enum Source {
+    /// A picture stream in the source file itself, by its index.
+    Embedded(usize),
+    /// An image next to the source file.
+    Folder(PathBuf),
+}
+
+/// How cover art is embedded into transcoded files.
+pub struct CoverArt {
+    /// The file names to look for next to a source file if it has no embedded cover, in order of
+    /// preference. Case doesn't matter.
+    pub names: Vec<String>,
+    /// Downscale the cover so neither side is larger than this many pixels.
+    pub max_size: Option<u32>,
+}
+
+/// The FFmpeg arguments that embed a cover.
+#[derive(Default)]
+pub struct CoverArgs {
+    /// Extra inputs, placed right after the source file.
+    pub inputs: Vec<String>,
+    /// Output options, placed before the output file.
+    pub outputs: Vec<String>,
+    /// A file with the metadata to write, which replaces the source's metadata.
+    pub metadata_file: Option<MetadataFile>,
+}
+
+/// A temporary FFmpeg metadata file with the cover in it. It should be written right before the
+/// job that reads it and deleted once the job is over, since it holds the whole cover.
+pub struct MetadataFile {
+    pub path: PathBuf,
+    src: PathBuf,
+    source: Source,
+    tags: Tags,
+    max_size: Option<u32>,
+}
+
+impl MetadataFile {
+    /// Extracts the cover and writes it to the file, along with the source's tags.
+    pub fn write(&self) -> Result<()> {
+        let picture = read_picture(&self.src, &self.source, self.max_size)?;
+        let mut tags = Tags(self.tags.0.clone());
+        tags.0
+            .retain(|key, _| !key.eq_ignore_ascii_case("METADATA_BLOCK_PICTURE"));
+        tags.0.insert(
+            "METADATA_BLOCK_PICTURE".to_string(),
+            STANDARD.encode(picture),
+        );
+        fs::write(&self.path, ffmetadata(&tags))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    /// Deletes the file, if it was written.
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl CoverArgs {
+    /// The index of the input the metadata should be taken from.
+    pub fn metadata_input(&self) -> &'static str {
+        if self.metadata_file.is_some() {
+            "1"
+        } else {
+            "0"
+        }
+    }
+}
+
+impl CoverArt {
+    /// Gets the arguments to embed the cover of `src` into an output with the given container. If
+    /// there's no cover, the arguments only drop any other video streams.
+    pub fn args(&self, src: &Path, container: &str) -> Result<CoverArgs> {
+        let Some(method) = Method::for_container(container) else {
+            warn!(
+                "Can't embed cover art into '{}' files, so {} won't have any.",
+                container,
+                src.display()
+            );
+            return Ok(CoverArgs::default());
+        };
+        let info = probe::probe(src)?;
+        let source = match info.streams.iter().find(|s| s.disposition.attached_pic) {
+            Some(stream) => Source::Embedded(stream.index),
+            None => match self.find_folder_cover(src)? {
+                Some(path) => Source::Folder(path),
+                // Ogg can't hold the picture stream, so make sure it's left out.
+                None => {
+                    return Ok(CoverArgs {
+                        outputs: vec!["-map".to_string(), "0:a".to_string()],
+                        ..Default::default()
+                    })
+                }
+            },
+        };
+
+        match method {
+            Method::AttachedPic => self.attached_pic_args(source),
+            Method::BlockPicture => {
+                let mut tags = info.format.tags;
+                if let Some(stream) = info
+                    .streams
+                    .iter()
+                    .find(|s| s.codec_type == CodecType::Audio)
+                {
+                    // Ogg sources keep their tags on the audio stream.
+                    tags.0.extend(stream.tags.0.clone());
+                }
+                self.block_picture_args(src, source, tags)
+            }
+        }
+    }
+
+    fn attached_pic_args(&self, source: Source) -> Result<CoverArgs> {
+        let (inputs, stream) = match source {
+            Source::Embedded(index) => (vec![], format!("0:{}", index)),
+            Source::Folder(path) => (
+                vec!["-i".to_string(), path_to_str!(path)?.to_string()],
+                "1:v:0".to_string(),
+            ),
+        };
+        let mut outputs = vec![
+            "-map".to_string(),
+            "0:a".to_string(),
+            "-map".to_string(),
+            stream,
+        ];
+        match self.max_size.map(scale_filter) {
+            Some(filter) => outputs.extend([
+                "-filter:v".to_string(),
+                filter,
+                "-c:v".into(),
+                "mjpeg".into(),
+            ]),
+            None => outputs.extend(["-c:v".to_string(), "copy".to_string()]),
+        }
+        outputs.extend(["-disposition:v:0".to_string(), "attached_pic".to_string()]);
+        Ok(CoverArgs {
+            inputs,
+            outputs,
+            metadata_file: None,
+        })
+    }
+
+    fn block_picture_args(&self, src: &Path, source: Source, tags: Tags) -> Result<CoverArgs> {
+        let metadata_file = MetadataFile {
+            path: temp_path("txt"),
+            src: src.to_path_buf(),
+            source,
+            tags,
+            max_size: self.max_size,
+        };
+        Ok(CoverArgs {
+            inputs: vec![
+                "-f".to_string(),
+                "ffmetadata".to_string(),
+                "-i".to_string(),
+                path_to_str!(metadata_file.path)?.to_string(),
+            ],
+            outputs: vec!["-map".to_string(), "0:a".to_string()],
+            metadata_file: Some(metadata_file),
+        })
+    }
+
+    /// Looks for a cover image next to `src`.
+    fn find_folder_cover(&self, src: &Path) -> Result<Option<PathBuf>> {
+        let Some(dir) = src.parent() else {
+            return Ok(None);
+        };
+        let files = fs::read_dir(dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>();
+        Ok(self.names.iter().find_map(|name| {
+            files
+                .iter()
+                .find(|p| {
+                    p.file_name()
+                        .is_some_and(|f| f.to_string_lossy().eq_ignore_ascii_case(name))
+                })
+                .cloned()
+        }))
+    }
+}
+
+/// Extracts the cover, resizing it if needed, and builds a FLAC picture block from it.
+fn read_picture(src: &Path, source: &Source, max_size: Option<u32>) -> Result<Vec<u8>> {
+    let (input, stream) = match source {
+        Source::Embedded(index) => (src, format!("0:{}", index)),
+        Source::Folder(path) => (path.as_path(), "0:v:0".to_string()),
+    };
+    let image = temp_path(if max_size.is_some() { "jpg" } else { "img" });
+    let mut args = vec![
+        "-y".to_string(),
+        "-i".to_string(),
+        path_to_str!(input)?.to_string(),
+        "-map".to_string(),
+        stream,
+    ];
+    match max_size.map(scale_filter) {
+        Some(filter) => args.extend([
+            "-filter:v".to_string(),
+            filter,
+            "-c:v".into(),
+            "mjpeg".into(),
+        ]),
+        None => args.extend(["-c:v".to_string(), "copy".to_string()]),
+    }
+    args.extend(["-frames:v", "1", "-update", "1", "-f", "image2"].map(String::from));
+    args.push(path_to_str!(image)?.to_string());
+
+    let result = utils::run_ffmpeg(true, &args, |_| {})
+        .with_context(|| format!("Failed to extract the cover for {}", src.display()))
+        .and_then(|()| picture_block(&image));
+    let _ = fs::remove_file(&image);
+    result
+}
+
+/// Builds a FLAC picture block (as used by METADATA_BLOCK_PICTURE) from an image file.
+fn picture_block(image: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(image).with_context(|| format!("Failed to read {}", image.display()))?;
+    let info = probe::probe(image)?;
+    let stream = info
+        .streams
+        .first()
+        .with_context(|| format!("{} isn't an image", image.display()))?;
+    let mime = match stream.codec_name.as_deref() {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        _ => "image/jpeg",
+    };
+
+    let mut block = Vec::with_capacity(data.len() + 64);
+    block.extend_from_slice(&FRONT_COVER.to_be_bytes());
+    block.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    block.extend_from_slice(mime.as_bytes());
+    for n in [
+        // The length of the description, which is left empty.
+        0,
+        stream.width.unwrap_or_default(),
+        stream.height.unwrap_or_default(),
+        // The colour depth, and the number of colours for indexed images.
+        24,
+        0,
+        data.len() as u32,
+    ] {
+        block.extend_from_slice(&n.to_be_bytes());
+    }
+    block.extend_from_slice(&data);
+    Ok(block)
+}
+
+/// Formats tags as an FFmpeg metadata file.
+fn ffmetadata(tags: &Tags) -> String {
+    let escape = |s: &str| {
+        s.chars().fold(String::new(), |mut out, c| {
+            if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+                out.push('\\');
+            }
+            out.push(c);
+            out
+        })
+    };
+    let mut out = ";FFMETADATA1\n".to_string();
+    for (key, value) in &tags.0 {
+        out.push_str(&format!("{}={}\n", escape(key), escape(value)));
+    }
+    out
+}
+
+/// Gets a filter that shrinks an image so neither side is larger than `size`. Smaller images are
+/// left as they are.
+pub(super) fn scale_filter(size: u32) -> String {
+    format!("scale=w='min(iw,{size})':h='min(ih,{size})':force_original_aspect_ratio=decrease")
+}
+
+fn temp_path(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "mediatools-cover-{}-{}.{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        extension
+    ))
+}
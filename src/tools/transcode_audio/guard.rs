@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use simplelog::warn;
+
+use super::codecs::{self, Codec};
+use crate::probe::{self, CodecType};
+
+/// What to do with sources that are already lossy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LossyPolicy {
+    /// Transcode them without complaining.
+    Allow,
+    /// Transcode them, but warn about each one.
+    Warn,
+    /// Skip them.
+    Refuse,
+}
+
+/// Keeps files from being transcoded in ways that lose quality for no good reason.
+pub struct SourceGuard {
+    lossy: LossyPolicy,
+    allow_upscale: bool,
+    target: &'static Codec,
+    /// The bitrate being transcoded to in kbit/s, if one was given.
+    target_kbps: Option<u32>,
+}
+
+impl SourceGuard {
+    pub fn new(
+        lossy: LossyPolicy,
+        allow_upscale: bool,
+        target: &'static Codec,
+        target_kbps: Option<u32>,
+    ) -> Self {
+        Self {
+            lossy,
+            allow_upscale,
+            target,
+            target_kbps,
+        }
+    }
+
+    /// Checks whether `src` should be transcoded. Returns why it shouldn't be, if it shouldn't.
+    pub fn check(&self, src: &Path) -> Result<Option<String>> {
+        // Nothing is lost when transcoding to a lossless codec.
+        if self.target.lossless {
+            return Ok(None);
+        }
+        let check_bitrate = self.target_kbps.is_some() && !self.allow_upscale;
+        if self.lossy == LossyPolicy::Allow && !check_bitrate {
+            return Ok(None);
+        }
+
+        let info = match probe::probe(src) {
+            Ok(info) => info,
+            // Only refusing needs to know, so otherwise let FFmpeg have a go at it.
+            Err(e) if self.lossy == LossyPolicy::Refuse => {
+                return Ok(Some(format!("ffprobe couldn't read it: {:#}", e)))
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't check whether {} is lossy, so transcoding it anyway: {:#}",
+                    src.display(),
+                    e
+                );
+                return Ok(None);
+            }
+        };
+        let Some(stream) = info
+            .streams
+            .iter()
+            .find(|s| s.codec_type == CodecType::Audio)
+        else {
+            return Ok(Some("it has no audio stream".to_string()));
+        };
+        let codec = stream.codec_name.as_deref().unwrap_or("unknown");
+        if codecs::is_lossless(codec) {
+            return Ok(None);
+        }
+
+        match self.lossy {
+            LossyPolicy::Allow => {}
+            LossyPolicy::Warn => warn!(
+                "{} is already lossy ({}), so transcoding it to {} will lose quality.",
+                src.display(),
+                codec,
+                self.target.name
+            ),
+            LossyPolicy::Refuse => {
+                return Ok(Some(format!(
+                    "it's already lossy ({}), so transcoding it would lose quality. Use \
+                     '--lossy-source allow' to transcode it anyway.",
+                    codec
+                )))
+            }
+        }
+
+        let source_kbps = stream
+            .bit_rate
+            .or(info.format.bit_rate)
+            .map(|bps| (bps as f64 / 1000.0).round() as u32);
+        match (self.target_kbps, source_kbps) {
+            (Some(target), Some(source)) if check_bitrate && target > source => Ok(Some(format!(
+                "the target bitrate of {}k is higher than the source's {}k. Use \
+                     '--allow-upscale' to transcode it anyway.",
+                target, source
+            ))),
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use lazy_regex::{lazy_regex, Lazy, Regex};
+use serde::{Deserialize, Serialize};
+
+use super::sync::SourceStamp;
+use crate::{
+    path_to_str, plan,
+    probe::{self, CodecType},
+    utils,
+};
+
+/// The loudness range loudnorm aims for, unless the source's is wider. Keeping it at least as wide
+/// as the source's lets loudnorm normalize linearly instead of compressing.
+const DEFAULT_LRA: f64 = 11.0;
+/// The widest loudness range loudnorm accepts.
+const MAX_LRA: f64 = 50.0;
+
+/// The JSON loudnorm prints at the end of its first pass.
+static MEASUREMENT_PATTERN: Lazy<Regex> = lazy_regex!(r"(?s)\{[^{}]*input_i[^{}]*\}");
+
+/// What to normalize the loudness to.
+#[derive(Debug, Clone, Copy)]
+pub struct Normalize {
+    /// The target integrated loudness in LUFS, e.g. -16 or -23.
+    pub target: f64,
+    /// The highest true peak allowed in dBTP.
+    pub true_peak: f64,
+}
+
+/// What loudnorm measured in its first pass. The values are kept as FFmpeg printed them, so
+/// they're passed back to it exactly.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct Measurement {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+    /// loudnorm always outputs 192 kHz, so the source's rate is restored afterwards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<u32>,
+}
+
+impl Measurement {
+    /// Stands in for a measurement in a dry run, which doesn't measure anything.
+    fn placeholder(sample_rate: Option<u32>) -> Self {
+        let value = || "MEASURED".to_string();
+        Self {
+            input_i: value(),
+            input_tp: value(),
+            input_lra: value(),
+            input_thresh: value(),
+            target_offset: value(),
+            sample_rate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    source: SourceStamp,
+    target: f64,
+    true_peak: f64,
+    measurement: Measurement,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Cache {
+    /// Keyed by the absolute path of the source file.
+    files: BTreeMap<PathBuf, CacheEntry>,
+}
+
+/// Normalizes loudness with two passes of FFmpeg's loudnorm filter. The first pass measures the
+/// source and is cached, so running again doesn't measure again unless the source has changed.
+pub struct Normalizer {
+    settings: Normalize,
+    cache_path: Option<PathBuf>,
+    cache: Mutex<Cache>,
+}
+
+impl Normalizer {
+    pub fn load(settings: Normalize) -> Result<Self> {
+        let cache_path = cache_path();
+        let cache = match &cache_path {
+            Some(path) if path.exists() => {
+                let raw = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                // A broken cache is only a reason to measure again.
+                serde_json::from_str(&raw).unwrap_or_default()
+            }
+            _ => Cache::default(),
+        };
+        Ok(Self {
+            settings,
+            cache_path,
+            cache: Mutex::new(cache),
+        })
+    }
+
+    /// Gets the filter that normalizes `src`, measuring it first if it isn't cached. Unless
+    /// `resample` is false, the filter also restores the source's sample rate. In a dry run,
+    /// uncached measurements are left as placeholders.
+    pub fn filter(&self, src: &Path, resample: bool) -> Result<String> {
+        let m = self.measure(src)?;
+        let measured_lra = m.input_lra.parse::<f64>().unwrap_or(DEFAULT_LRA);
+        let mut filter = format!(
+            "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:\
+             measured_thresh={}:offset={}:linear=true",
+            self.settings.target,
+            self.settings.true_peak,
+            measured_lra.clamp(DEFAULT_LRA, MAX_LRA),
+            m.input_i,
+            m.input_tp,
+            m.input_lra,
+            m.input_thresh,
+            m.target_offset
+        );
+        if let (Some(rate), true) = (m.sample_rate, resample) {
+            filter.push_str(&format!(",aresample={}", rate));
+        }
+        Ok(filter)
+    }
+
+    fn measure(&self, src: &Path) -> Result<Measurement> {
+        let key = src
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", src.display()))?;
+        let stamp = SourceStamp::read(src, false)?;
+        let cached = self
+            .cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .files
+            .get(&key)
+            .filter(|e| {
+                e.source == stamp
+                    && e.target == self.settings.target
+                    && e.true_peak == self.settings.true_peak
+            })
+            .map(|e| e.measurement.clone());
+        if let Some(measurement) = cached {
+            return Ok(measurement);
+        }
+
+        let sample_rate = probe::probe(src)?
+            .streams
+            .iter()
+            .find(|s| s.codec_type == CodecType::Audio)
+            .and_then(|s| s.sample_rate);
+        // Measuring decodes the whole file, which is too much for a dry run.
+        if plan::is_dry_run() {
+            return Ok(Measurement::placeholder(sample_rate));
+        }
+        let filter = format!(
+            "loudnorm=I={}:TP={}:LRA={}:print_format=json",
+            self.settings.target, self.settings.true_peak, DEFAULT_LRA
+        );
+        let output = utils::ffmpeg_output([
+            "-i",
+            path_to_str!(src)?,
+            "-map",
+            "0:a:0",
+            "-filter:a",
+            &filter,
+            "-f",
+            "null",
+            "-",
+        ])
+        .with_context(|| format!("Failed to measure the loudness of {}", src.display()))?;
+        let json = MEASUREMENT_PATTERN
+            .find_iter(&output)
+            .last()
+            .with_context(|| format!("loudnorm didn't report the loudness of {}", src.display()))?;
+        let measurement = Measurement {
+            sample_rate,
+            ..serde_json::from_str(json.as_str()).with_context(|| {
+                format!("Failed to parse loudnorm's output for {}", src.display())
+            })?
+        };
+
+        self.cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .files
+            .insert(
+                key,
+                CacheEntry {
+                    source: stamp,
+                    target: self.settings.target,
+                    true_peak: self.settings.true_peak,
+                    measurement: measurement.clone(),
+                },
+            );
+        Ok(measurement)
+    }
+
+    /// Writes the measurements to the cache. Does nothing in a dry run.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.cache_path else {
+            return Ok(());
+        };
+        if plan::is_dry_run() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        fs::write(path, serde_json::to_string_pretty(&*cache)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Where measurements are cached, in the user's cache directory.
+fn cache_path() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("mediatools").join("loudnorm.json"))
+}
@@ -0,0 +1,188 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use lazy_regex::{lazy_regex, Lazy, Regex};
+
+use super::{
+    codecs::{Codec, RateControl},
+    SourceFilter,
+};
+use crate::{
+    global_args,
+    jobs::{Job, JobQueue},
+    path_to_str,
+    plan::{self, Action},
+    probe::{self, CodecType},
+    utils, WalkArgs,
+};
+
+/// The FLAC compression level used unless another is given. It's the highest one that every
+/// decoder supports.
+pub const DEFAULT_LEVEL: u8 = 8;
+
+static MD5_PATTERN: Lazy<Regex> = lazy_regex!(r"MD5=([0-9a-f]{32})");
+
+/// Recompresses lossless files in place: WAV and AIFF become FLAC, and FLAC is encoded again at
+/// `level`. Each file is written to a temporary file next to it, which only replaces the original
+/// once its decoded audio has been verified to be identical.
+pub fn run(src_path: &Path, src_container: &str, level: u8, qffmpeg: bool) -> Result<()> {
+    let src_filter = SourceFilter::parse(src_container)?;
+    let rate_args = Codec::find("flac")?.rate_args(RateControl::Compression(level.into()))?;
+    let files = if src_path.is_file() {
+        vec![src_path.to_path_buf()]
+    } else {
+        let walk = WalkArgs {
+            recursive: true,
+            ..global_args().walk.clone()
+        };
+        src_filter.select(utils::walk_dir(src_path, &walk, |p| src_filter.matches(p))?)
+    };
+
+    let mut queue = JobQueue::new(qffmpeg);
+    for file in files {
+        // A run that was killed can leave its temporary file behind.
+        let stale = tmp_path(&file);
+        if stale.exists() {
+            if plan::is_dry_run() {
+                plan::record(Action::Delete { path: stale });
+            } else {
+                fs::remove_file(&stale)
+                    .with_context(|| format!("Failed to delete {}", stale.display()))?;
+            }
+        }
+        match check_source(&file) {
+            Ok(()) => queue.push(recompress_job(&file, &rate_args)?),
+            Err(e) => queue.skip(&file, format!("{:#}", e)),
+        }
+    }
+    queue.run()
+}
+
+/// Makes sure `src` can be stored as FLAC without losing anything.
+fn check_source(src: &Path) -> Result<()> {
+    let info = probe::probe(src)?;
+    let mut audio = info
+        .streams
+        .iter()
+        .filter(|s| s.codec_type == CodecType::Audio);
+    let stream = audio.next().context("It has no audio stream")?;
+    if audio.next().is_some() {
+        bail!("It has more than one audio stream");
+    }
+    match stream.codec_name.as_deref().unwrap_or_default() {
+        "flac" => {}
+        // FLAC only holds integer samples of up to 24 bits.
+        "pcm_u8" | "pcm_s8" | "pcm_s16le" | "pcm_s16be" | "pcm_s24le" | "pcm_s24be" => {}
+        codec if codec.starts_with("pcm_") => {
+            bail!("FLAC can't hold its {} audio losslessly", codec)
+        }
+        codec => bail!("It's {}, not WAV, AIFF or FLAC", codec),
+    }
+
+    let dest = src.with_extension("flac");
+    if dest != src && dest.exists() {
+        bail!("{} already exists", dest.display());
+    }
+    Ok(())
+}
+
+fn recompress_job(src: &Path, rate_args: &[String]) -> Result<Job> {
+    let dest = src.with_extension("flac");
+    let tmp_path = tmp_path(src);
+    let mut args = vec!["-y", "-i", path_to_str!(src)?, "-map", "0", "-c:a", "flac"];
+    args.extend(rate_args.iter().map(String::as_str));
+    // Keeps any cover art as it is. The format has to be given since the extension isn't FLAC's.
+    args.extend([
+        "-c:v",
+        "copy",
+        "-map_metadata",
+        "0",
+        "-f",
+        "flac",
+        path_to_str!(tmp_path)?,
+    ]);
+
+    let mut job = Job::new(src.display().to_string(), &tmp_path, args).plans(Action::Rename {
+        from: tmp_path.clone(),
+        to: dest.clone(),
+    });
+    if src != dest {
+        job = job.plans(Action::Delete {
+            path: src.to_path_buf(),
+        });
+    }
+    let src = src.to_path_buf();
+    let cleanup_path = tmp_path.clone();
+    Ok(job
+        .then(move || {
+            verify(&src, &tmp_path)?;
+            replace(&src, &tmp_path, &dest)
+        })
+        // Once the file is in place there's nothing left to remove, but if anything went wrong a
+        // partial or unverified file would be left in the archive.
+        .finally(move || {
+            let _ = fs::remove_file(&cleanup_path);
+        }))
+}
+
+/// Gets the temporary file `src` is recompressed to. Its extension isn't FLAC's so it's never
+/// taken for a source, e.g. if it's left behind because mediatools was killed.
+fn tmp_path(src: &Path) -> PathBuf {
+    src.with_file_name(format!(
+        ".{}.recompress-tmp",
+        src.file_stem().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+/// Checks that `a` and `b` decode to exactly the same audio.
+fn verify(a: &Path, b: &Path) -> Result<()> {
+    let (a_hash, b_hash) = (audio_md5(a)?, audio_md5(b)?);
+    if a_hash != b_hash {
+        bail!(
+            "The recompressed audio doesn't match the original (MD5 {} vs {}), so the original was kept",
+            b_hash,
+            a_hash
+        );
+    }
+    Ok(())
+}
+
+/// Hashes the decoded audio of a file. The samples are widened to 32 bits first so files with the
+/// same audio hash the same regardless of the sample format their decoder outputs.
+fn audio_md5(path: &Path) -> Result<String> {
+    let output = utils::ffmpeg_output([
+        "-i",
+        path_to_str!(path)?,
+        "-map",
+        "0:a:0",
+        "-c:a",
+        "pcm_s32le",
+        "-f",
+        "md5",
+        "-",
+    ])
+    .with_context(|| format!("Failed to decode {}", path.display()))?;
+    MD5_PATTERN
+        .captures_iter(&output)
+        .last()
+        .map(|c| c[1].to_string())
+        .with_context(|| format!("FFmpeg didn't print the MD5 of {}", path.display()))
+}
+
+/// Moves the verified file into place. Renaming within a directory is atomic, so the original is
+/// never left half written.
+fn replace(src: &Path, tmp_path: &Path, dest: &Path) -> Result<()> {
+    fs::rename(tmp_path, dest).with_context(|| {
+        format!(
+            "Failed to replace {} with the recompressed file",
+            dest.display()
+        )
+    })?;
+    if src != dest {
+        fs::remove_file(src).with_context(|| format!("Failed to delete {}", src.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use simplelog::info;
+
+use crate::plan::{self, Action};
+
+/// The name of the file the sync state is stored in, in the root of the destination directory.
+const STATE_FILE_NAME: &str = ".mediatools-sync.json";
+
+/// What a source file looked like when it was transcoded.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SourceStamp {
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    /// A hash of the file's contents, only set with '--sync-hash'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl SourceStamp {
+    pub fn read(path: &Path, hash: bool) -> Result<Self> {
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            hash: if hash { Some(hash_file(path)?) } else { None },
+        })
+    }
+
+    /// Whether the source has changed since `self` was recorded. If both have a hash, only the
+    /// contents matter, so touching a file doesn't cause it to be re-encoded.
+    fn differs_from(&self, old: &Self) -> bool {
+        match (&self.hash, &old.hash) {
+            (Some(new_hash), Some(old_hash)) => self.size != old.size || new_hash != old_hash,
+            _ => {
+                self.size != old.size
+                    || self.mtime_secs != old.mtime_secs
+                    || self.mtime_nanos != old.mtime_nanos
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Entry {
+    /// The output file, relative to the destination directory.
+    output: PathBuf,
+    source: SourceStamp,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct State {
+    /// Keyed by the source file, relative to the source directory.
+    files: BTreeMap<PathBuf, Entry>,
+    /// Outputs that were replaced by an output at a different path (like when the codec changed),
+    /// mapped to the source they were made from. They're deleted like orphans.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    replaced: BTreeMap<PathBuf, PathBuf>,
+}
+
+/// What needs to be done with a source file.
+pub enum SyncStatus {
+    /// The output exists and was made from the current version of the source.
+    UpToDate,
+    /// The output doesn't exist yet.
+    New(SourceStamp),
+    /// The output exists, but the source has changed since it was made.
+    Changed(SourceStamp),
+}
+
+/// Keeps track of which source file each output in a destination directory was transcoded from,
+/// so a library can be mirrored repeatedly without re-encoding everything.
+pub struct SyncState {
+    src_root: PathBuf,
+    dest_root: PathBuf,
+    hash: bool,
+    state: Arc<Mutex<State>>,
+}
+
+impl SyncState {
+    pub fn load(src_root: &Path, dest_root: &Path, hash: bool) -> Result<Self> {
+        let path = dest_root.join(STATE_FILE_NAME);
+        let state = if path.exists() {
+            let raw = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read sync state {}", path.display()))?;
+            serde_json::from_str(&raw)
+                .with_context(|| format!("Invalid sync state {}", path.display()))?
+        } else {
+            State::default()
+        };
+        Ok(Self {
+            src_root: src_root.to_path_buf(),
+            dest_root: dest_root.to_path_buf(),
+            hash,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Works out whether `src` has to be transcoded to `dest`.
+    pub fn check(&self, src: &Path, dest: &Path) -> Result<SyncStatus> {
+        let stamp = SourceStamp::read(src, self.hash)?;
+        let rel_src = src.strip_prefix(&self.src_root)?;
+        let rel_dest = dest.strip_prefix(&self.dest_root)?;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // If the output path changed, the old output is no longer made by anything.
+        if let Some(entry) = state.files.get(rel_src).filter(|e| e.output != rel_dest) {
+            let old_output = entry.output.clone();
+            state.files.remove(rel_src);
+            state.replaced.insert(old_output, rel_src.to_path_buf());
+        }
+        if !dest.exists() {
+            return Ok(SyncStatus::New(stamp));
+        }
+
+        let is_up_to_date = match state.files.get(rel_src) {
+            Some(entry) => !stamp.differs_from(&entry.source),
+            // Outputs from before syncing was used (or made by something else) are trusted as
+            // long as they're newer than their source.
+            None => fs::metadata(dest)?.modified()? >= fs::metadata(src)?.modified()?,
+        };
+        Ok(if is_up_to_date {
+            SyncStatus::UpToDate
+        } else {
+            SyncStatus::Changed(stamp)
+        })
+    }
+
+    /// Gets a hook that records `src` as transcoded to `dest`, for running once the job is done.
+    pub fn record_hook(
+        &self,
+        src: &Path,
+        dest: &Path,
+        stamp: SourceStamp,
+    ) -> Result<impl Fn() -> Result<()> + Send + Sync + 'static> {
+        let rel_src = src.strip_prefix(&self.src_root)?.to_path_buf();
+        let entry = Entry {
+            output: dest.strip_prefix(&self.dest_root)?.to_path_buf(),
+            source: stamp,
+        };
+        let state = Arc::clone(&self.state);
+        Ok(move || {
+            state
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .files
+                .insert(rel_src.clone(), entry.clone());
+            Ok(())
+        })
+    }
+
+    /// Deletes the outputs of all source files that no longer exist, and outputs that were
+    /// replaced by one at a different path. Run this after the jobs, so a replaced output is only
+    /// deleted once its replacement has been made.
+    pub fn delete_orphans(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let orphans = state
+            .files
+            .keys()
+            .filter(|rel_src| !self.src_root.join(rel_src).exists())
+            .cloned()
+            .collect::<Vec<_>>();
+        for rel_src in orphans {
+            let output = self.dest_root.join(&state.files[&rel_src].output);
+            if !delete_output(&output, "its source was removed")? {
+                continue;
+            }
+            state.files.remove(&rel_src);
+        }
+
+        let current = state
+            .files
+            .values()
+            .map(|entry| entry.output.clone())
+            .collect::<BTreeSet<_>>();
+        let replaced = state
+            .replaced
+            .iter()
+            .filter(|(output, rel_src)| {
+                // If the source's new output failed, the old one is kept for now.
+                !current.contains(*output)
+                    && (plan::is_dry_run()
+                        || state.files.contains_key(*rel_src)
+                        || !self.src_root.join(rel_src).exists())
+            })
+            .map(|(output, _)| output.clone())
+            .collect::<Vec<_>>();
+        for rel_output in replaced {
+            let output = self.dest_root.join(&rel_output);
+            if delete_output(&output, "it was replaced by a new output")? {
+                state.replaced.remove(&rel_output);
+            }
+        }
+        // Outputs that are made again (like after switching back to an earlier codec) aren't
+        // orphans anymore.
+        state.replaced.retain(|output, _| !current.contains(output));
+        Ok(())
+    }
+
+    /// Writes the state back to the destination directory. Does nothing in a dry run.
+    pub fn save(&self) -> Result<()> {
+        if plan::is_dry_run() {
+            return Ok(());
+        }
+        let path = self.dest_root.join(STATE_FILE_NAME);
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        fs::write(&path, serde_json::to_string_pretty(&*state)?)
+            .with_context(|| format!("Failed to write sync state {}", path.display()))
+    }
+}
+
+/// Deletes an orphaned output, or records that it would be in a dry run. Returns whether it's gone.
+fn delete_output(output: &Path, reason: &str) -> Result<bool> {
+    if plan::is_dry_run() {
+        plan::record(Action::Delete {
+            path: output.to_path_buf(),
+        });
+        return Ok(false);
+    }
+    if output.exists() {
+        fs::remove_file(output)
+            .with_context(|| format!("Failed to delete {}", output.display()))?;
+        info!("Deleted {} since {}", output.display(), reason);
+    }
+    Ok(true)
+}
+
+/// Hashes a file's contents with 64-bit FNV-1a. It's not cryptographic, but it's stable across
+/// versions, which is all that's needed to notice changes.
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buf[..read] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(format!("{:016x}", hash))
+}
@@ -1,21 +1,43 @@
-use anyhow::{Context, Result};
-use std::{fs, path::Path};
+use anyhow::{bail, Context, Result};
+use std::path::Path;
 
-use crate::{path_to_str, utils};
+use crate::{
+    config, global_args,
+    jobs::{Job, JobQueue},
+    path_to_str, utils,
+};
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     src_path: &Path,
     dest_path: &Path,
-    preset: u8,
-    crf: u8,
-    keyframe_interval: u16,
+    preset: Option<u8>,
+    crf: Option<u8>,
+    keyframe_interval: Option<u16>,
     force_10bit: bool,
+    profile: Option<&str>,
     overwrite: bool,
     qffmpeg: bool,
 ) -> Result<()> {
-    fs::create_dir_all(dest_path)?;
+    if profile.is_none() && (preset.is_none() || crf.is_none() || keyframe_interval.is_none()) {
+        bail!("The preset, CRF and keyframe interval are required when not using a profile!");
+    }
+    let profile = config::load_profile(profile)?;
+    let encoder = profile.encoder.as_deref().unwrap_or("libsvtav1");
+    let params = profile.params_args(&[
+        ("preset", preset.map(|p| p.to_string())),
+        ("crf", crf.map(|c| c.to_string())),
+        ("g", keyframe_interval.map(|g| g.to_string())),
+    ]);
+    let pixel_format = if force_10bit {
+        Some("yuv420p10le")
+    } else {
+        profile.pixel_format.as_deref()
+    };
+
+    let mut queue = JobQueue::new(qffmpeg);
 
-    for path in utils::read_dir(src_path, |p| {
+    for path in utils::walk_dir(src_path, &global_args().walk, |p| {
         p.is_file()
             && p.extension()
                 .map(|e| e.to_string_lossy().to_lowercase())
@@ -23,11 +45,10 @@ pub fn run(
                 .is_some()
     })? {
         let rel_path = path.strip_prefix(src_path)?;
-        let out_path = dest_path.join(rel_path);
-
-        let preset_str = &preset.to_string();
-        let crf_str = &crf.to_string();
-        let keyframe_interval_str = &keyframe_interval.to_string();
+        let mut out_path = dest_path.join(rel_path);
+        if let Some(container) = &profile.container {
+            out_path.set_extension(container);
+        }
 
         let mut args = vec![
             if overwrite { "-y" } else { "-n" },
@@ -38,24 +59,25 @@ pub fn run(
             "-c",
             "copy",
             "-c:v",
-            "libsvtav1",
-            "-preset",
-            preset_str,
-            "-crf",
-            crf_str,
-            "-g",
-            keyframe_interval_str,
+            encoder,
         ];
+        args.extend(params.iter().map(String::as_str));
+        if let Some(bitrate) = &profile.bitrate {
+            args.push("-b:v");
+            args.push(bitrate);
+        }
 
-        if force_10bit {
+        if let Some(pixel_format) = pixel_format {
             args.push("-pix_fmt");
-            args.push("yuv420p10le");
+            args.push(pixel_format);
         }
 
+        args.extend(profile.extra_args.iter().map(String::as_str));
+
         args.push(path_to_str!(out_path)?);
 
-        utils::run_ffmpeg(qffmpeg, args)?;
+        queue.push(Job::new(rel_path.display().to_string(), &out_path, args));
     }
 
-    Ok(())
+    queue.run()
 }
@@ -1,65 +1,282 @@
 use std::{
     ffi::OsStr,
     fs,
+    io::{self, BufRead, BufReader, Read},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, ExitStatus, Stdio},
+    sync::Mutex,
+    thread,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
+use lazy_regex::{lazy_regex, Lazy, Regex};
 use simplelog::{error, info};
+use thiserror::Error;
 
-pub fn read_dir<P>(path: &Path, predicate: P) -> Result<Vec<PathBuf>>
+use crate::{progress::FfmpegProgress, WalkArgs};
+
+/// Finds all files in a directory matching `predicate`, searching subdirectories as configured by
+/// `walk`. The returned paths are sorted, so files in the same directory stay together.
+pub fn walk_dir<P>(root: &Path, walk: &WalkArgs, predicate: P) -> Result<Vec<PathBuf>>
 where
     P: Fn(&PathBuf) -> bool,
 {
-    let mut paths = fs::read_dir(path)
-        .with_context(|| format!("Failed to read directory: {}", path.display()))?
-        .filter_map(|r| r.ok().map(|e| e.path()).take_if(|p| predicate(p)))
-        .collect::<Vec<PathBuf>>();
-    paths.sort_unstable();
-    Ok(paths)
+    let max_depth = if walk.recursive {
+        walk.max_depth.map_or(usize::MAX, NonZeroUsize::get)
+    } else {
+        1
+    };
+
+    let mut files = Vec::new();
+    let mut dirs = vec![(root.to_path_buf(), 1)];
+    while let Some((dir, depth)) = dirs.pop() {
+        for entry in fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("Failed to access entry in: {}", dir.display()))?
+                .path();
+            let rel_path = path.strip_prefix(root)?;
+            if walk.exclude.iter().any(|p| p.matches_path(rel_path)) {
+                continue;
+            }
+            if path.is_dir() {
+                if depth < max_depth {
+                    dirs.push((path, depth + 1));
+                }
+            } else if (walk.include.is_empty()
+                || walk.include.iter().any(|p| p.matches_path(rel_path)))
+                && predicate(&path)
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Held while printing FFmpeg's output, so the output of concurrent jobs doesn't get interleaved.
+static FFMPEG_OUTPUT_LOCK: Mutex<()> = Mutex::new(());
+
+/// How many lines from the end of FFmpeg's output to keep in errors.
+const STDERR_TAIL_LINES: usize = 20;
+
+static OUTPUT_EXISTS_PATTERN: Lazy<Regex> = lazy_regex!(r"File '(.*)' already exists");
+static UNKNOWN_ENCODER_PATTERN: Lazy<Regex> = lazy_regex!(r"Unknown encoder '([^']*)'");
+
+/// Why an FFmpeg invocation failed. Every variant that comes from a finished FFmpeg process
+/// carries the last lines it printed.
+#[derive(Debug, Error)]
+pub enum FfmpegError {
+    #[error("FFmpeg couldn't be found! Make sure it's installed and on your PATH.")]
+    NotFound,
+    #[error("The output file {path} already exists")]
+    OutputExists { path: PathBuf, stderr_tail: String },
+    #[error("FFmpeg doesn't know the encoder '{encoder}'")]
+    UnknownEncoder {
+        encoder: String,
+        stderr_tail: String,
+    },
+    #[error("FFmpeg was given an invalid argument")]
+    InvalidArgument { stderr_tail: String },
+    #[error("FFmpeg was killed by signal {signal}")]
+    Killed { signal: i32, stderr_tail: String },
+    #[error("FFmpeg exited with code {code}")]
+    NonZeroExit { code: i32, stderr_tail: String },
+    #[error("Failed to communicate with FFmpeg")]
+    Io(#[from] io::Error),
+}
+
+impl FfmpegError {
+    /// Works out why FFmpeg failed from its exit status and output.
+    fn from_status(status: ExitStatus, stderr: &str) -> Self {
+        let lines = stderr.lines().collect::<Vec<_>>();
+        let stderr_tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+
+        if let Some(signal) = exit_signal(status) {
+            return Self::Killed {
+                signal,
+                stderr_tail,
+            };
+        }
+        // FFmpeg's exit code is always 1 on failure, so its output is the only way to tell what
+        // actually went wrong. This is only done once we know it failed though.
+        if let Some(captures) = OUTPUT_EXISTS_PATTERN.captures(stderr) {
+            return Self::OutputExists {
+                path: PathBuf::from(&captures[1]),
+                stderr_tail,
+            };
+        }
+        if let Some(captures) = UNKNOWN_ENCODER_PATTERN.captures(stderr) {
+            return Self::UnknownEncoder {
+                encoder: captures[1].to_string(),
+                stderr_tail,
+            };
+        }
+        if stderr.contains("Invalid argument") {
+            return Self::InvalidArgument { stderr_tail };
+        }
+        Self::NonZeroExit {
+            code: status.code().unwrap_or(-1),
+            stderr_tail,
+        }
+    }
+
+    /// The last lines FFmpeg printed before failing, if it got that far.
+    pub fn stderr_tail(&self) -> Option<&str> {
+        match self {
+            Self::OutputExists { stderr_tail, .. }
+            | Self::UnknownEncoder { stderr_tail, .. }
+            | Self::InvalidArgument { stderr_tail }
+            | Self::Killed { stderr_tail, .. }
+            | Self::NonZeroExit { stderr_tail, .. } => Some(stderr_tail),
+            Self::NotFound | Self::Io(_) => None,
+        }
+    }
 }
 
-pub fn run_ffmpeg<I, S>(quiet: bool, args: I) -> Result<()>
+#[cfg(unix)]
+fn exit_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Runs FFmpeg with the given args, calling `on_progress` every time FFmpeg reports its progress.
+pub fn run_ffmpeg<I, S, F>(quiet: bool, args: I, mut on_progress: F) -> Result<(), FfmpegError>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
+    F: FnMut(&FfmpegProgress),
 {
     let mut cmd = Command::new("ffmpeg");
-    cmd.args(args);
+    cmd.args(["-progress", "pipe:1", "-nostats"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
 
     info!("{:?}", cmd);
-    let output = cmd.output()?;
+    let mut child = cmd.spawn().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => FfmpegError::NotFound,
+        _ => FfmpegError::Io(e),
+    })?;
+
+    // Stderr has to be drained while we're reading the progress from stdout, otherwise FFmpeg
+    // will block once the pipe fills up.
+    let stderr_reader = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        })
+    });
+
+    let mut progress = FfmpegProgress::default();
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            if progress.update(&line?) {
+                on_progress(&progress);
+            }
+        }
+    }
+
+    let status = child.wait()?;
+    let stderr = match stderr_reader.map(|r| r.join()) {
+        Some(Ok(stderr)) => stderr?,
+        _ => Vec::new(),
+    };
+    let stderr = String::from_utf8_lossy(&stderr);
 
-    let mut failed = false;
+    let _lock = FFMPEG_OUTPUT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     // FFmpeg outputs everything to stderr, *not* stdout!
-    // This means we have to guess what's an error and what isn't.
-    // It's hacky and isn't fool-proof but it's good enough.
-    for line in String::from_utf8_lossy(&output.stderr).lines() {
-        let out = format!("<green>[FFmpeg]</> {}", line);
-        if line.contains("Error")
-            || line.contains("Conversion failed")
-            || line.contains("Unknown encoder")
-            || line.contains("Invalid argument")
-        {
-            error!("{}", out);
-            failed = true;
-        } else if !quiet {
-            info!("{}", out)
+    if !quiet {
+        for line in stderr.lines() {
+            info!("<green>[FFmpeg]</> {}", line);
         }
     }
-    if failed {
-        bail!(
-            "Aborting due to ffmpeg failure!{}",
-            if quiet {
-                " Run without '-q' or '--qffmpeg' to see the full ffmpeg output for why it failed."
-            } else {
-                ""
-            }
-        );
+    if status.success() {
+        return Ok(());
     }
 
-    Ok(())
+    let err = FfmpegError::from_status(status, &stderr);
+    // Show why it failed even if the output is hidden, but not when it's only refusing to
+    // overwrite a file since that's expected with '-n'.
+    if quiet && !matches!(err, FfmpegError::OutputExists { .. }) {
+        for line in err.stderr_tail().unwrap_or_default().lines() {
+            error!("<green>[FFmpeg]</> {}", line);
+        }
+    }
+    Err(err)
+}
+
+/// Runs FFmpeg for what it prints rather than for a file it writes, e.g. to run an analysis
+/// filter. Returns everything FFmpeg printed.
+pub fn ffmpeg_output<I, S>(args: I) -> Result<String, FfmpegError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats"])
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => FfmpegError::NotFound,
+            _ => FfmpegError::Io(e),
+        })?;
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if output.status.success() {
+        // Muxers like md5 write their result to stdout.
+        Ok(stderr + &String::from_utf8_lossy(&output.stdout))
+    } else {
+        Err(FfmpegError::from_status(output.status, &stderr))
+    }
+}
+
+/// Formats rows of cells into aligned columns, with a header row on top.
+pub fn format_table(header: &[&str], rows: &[Vec<String>]) -> Vec<String> {
+    let mut widths = header.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    std::iter::once(format_row(header.to_vec()))
+        .chain(
+            rows.iter()
+                .map(|row| format_row(row.iter().map(String::as_str).collect())),
+        )
+        .collect()
+}
+
+/// Quotes an argument so a shell passes it through as-is.
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+    if is_safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
 }
 
 #[macro_export]